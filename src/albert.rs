@@ -9,16 +9,18 @@ use openssl::{
     pkey::{PKey, PKeyRef, Private},
     rsa::{Padding, Rsa},
     sign::Signer,
-    x509::{X509NameBuilder, X509ReqBuilder},
+    x509::{X509NameBuilder, X509ReqBuilder, X509},
 };
-use plist::Data;
+use plist::{Data, Dictionary};
 use uuid::Uuid;
 
 use regex::Regex;
+use reqwest::{Method, StatusCode};
 use serde::Serialize;
 
 use crate::{
-    util::{get_nested_value, make_reqwest, plist_to_buf, plist_to_string, KeyPair},
+    config::{api_request, get_config, ActivationDevice},
+    util::{plist_to_buf, plist_to_string, KeyPair},
     PushError,
 };
 
@@ -46,7 +48,15 @@ struct ActivationRequest {
     fair_play_signature: Data,
 }
 
-fn build_activation_info(private_key: &PKeyRef<Private>) -> Result<ActivationInfo, ErrorStack> {
+fn build_activation_info(
+    private_key: &PKeyRef<Private>,
+    device: &ActivationDevice,
+) -> Result<ActivationInfo, ErrorStack> {
+    // unset, the CSR and the activation each get their own random UUID
+    let random_id = || Uuid::new_v4().to_string();
+    let common_name = device.unique_device_id.clone().unwrap_or_else(random_id);
+    let unique_device_id = device.unique_device_id.clone().unwrap_or_else(random_id);
+
     let mut csr_builder = X509ReqBuilder::new()?;
     let mut name = X509NameBuilder::new()?;
     name.append_entry_by_nid(Nid::COUNTRYNAME, "US")?;
@@ -54,7 +64,7 @@ fn build_activation_info(private_key: &PKeyRef<Private>) -> Result<ActivationInf
     name.append_entry_by_nid(Nid::LOCALITYNAME, "Cupertino")?;
     name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "Apple Inc.")?;
     name.append_entry_by_nid(Nid::ORGANIZATIONALUNITNAME, "iPhone")?;
-    name.append_entry_by_nid(Nid::COMMONNAME, &Uuid::new_v4().to_string())?;
+    name.append_entry_by_nid(Nid::COMMONNAME, &common_name)?;
     csr_builder.set_subject_name(&name.build())?;
     csr_builder.set_version(0)?;
     csr_builder.set_pubkey(private_key)?;
@@ -65,13 +75,13 @@ fn build_activation_info(private_key: &PKeyRef<Private>) -> Result<ActivationInf
     Ok(ActivationInfo {
         activation_randomness: Uuid::new_v4().to_string(),
         activation_state: "Unactivated".to_string(),
-        build_version: "10.6.4".to_string(),
+        build_version: device.build_version.clone(),
         device_cert_request: pem.into(),
-        device_class: "Windows".to_string(),
-        product_type: "windows1,1".to_string(),
-        product_version: "10.6.4".to_string(),
-        serial_number: "WindowSerial".to_string(),
-        unique_device_id: Uuid::new_v4().to_string(),
+        device_class: device.device_class.clone(),
+        product_type: device.product_type.clone(),
+        product_version: device.product_version.clone(),
+        serial_number: device.serial_number.clone(),
+        unique_device_id,
     })
 }
 
@@ -82,7 +92,8 @@ pub async fn generate_push_cert() -> Result<KeyPair, PushError> {
         2048,
         BigNum::from_u32(65537)?.as_ref(),
     )?)?;
-    let activation_info = build_activation_info(private_key.as_ref())?;
+    let config = get_config();
+    let activation_info = build_activation_info(private_key.as_ref(), &config.device.activation)?;
 
     info!(
         "Generated activation info (with UUID: {})",
@@ -109,44 +120,75 @@ pub async fn generate_push_cert() -> Result<KeyPair, PushError> {
     };
 
     // activate with apple
-    let client = make_reqwest();
     let form = [("activation-info", plist_to_string(&request)?)];
-    let resp = client
-        .post(
-            "https://albert.apple.com/WebObjects/ALUnbrick.woa/wa/deviceActivation?device=Windows",
-        )
+    let resp = api_request(Method::POST, &config.endpoints.albert_activation)
+        .query(&[("device", &config.device.activation.device_class)])
         .form(&form)
         .send()
         .await?;
+    let status = resp.status();
     let text = resp.text().await?;
 
-    // parse protocol from HTML
-    let protocol_raw = Regex::new(r"<Protocol>(.*)</Protocol>")
+    Ok(KeyPair {
+        private: private_key.rsa().unwrap().private_key_to_der()?,
+        cert: parse_activation_response(status, &text)?,
+    })
+}
+
+fn find_error_message(dict: &Dictionary) -> Option<String> {
+    ["ErrorMessage", "ErrorTitle", "ErrorCode"]
+        .iter()
+        .find_map(|key| match dict.get(key)? {
+            plist::Value::String(message) => Some(message.clone()),
+            plist::Value::Integer(code) => Some(code.to_string()),
+            _ => None,
+        })
+}
+
+// pulls the DER device certificate out of an Albert deviceActivation response
+pub fn parse_activation_response(status: StatusCode, body: &str) -> Result<Vec<u8>, PushError> {
+    if !status.is_success() {
+        return Err(PushError::AlbertStatusError(status));
+    }
+
+    // the plist is wrapped in HTML
+    let protocol_raw = Regex::new(r"(?s)<Protocol>(.*)</Protocol>")
         .unwrap()
-        .captures(&text)
+        .captures(body)
         .ok_or(PushError::AlbertCertParseError)?
         .get(1)
         .unwrap();
+    let protocol = plist::Value::from_reader(Cursor::new(protocol_raw.as_str().trim()))
+        .map_err(|_| PushError::AlbertCertParseError)?;
+    let protocol = protocol
+        .as_dictionary()
+        .ok_or(PushError::AlbertCertParseError)?;
+
+    let activation = protocol
+        .get("device-activation")
+        .and_then(|v| v.as_dictionary());
+    let Some(record) = activation
+        .and_then(|activation| activation.get("activation-record"))
+        .and_then(|v| v.as_dictionary())
+    else {
+        // refusals come back as an ack without a record
+        let message = activation
+            .and_then(find_error_message)
+            .or_else(|| find_error_message(protocol))
+            .unwrap_or_else(|| "no activation record".to_string());
+        return Err(PushError::AlbertActivationRefused(message));
+    };
 
-    let protocol = plist::Value::from_reader(Cursor::new(protocol_raw.as_str()))?;
-    let certificate = get_nested_value(
-        &protocol,
-        &[
-            "device-activation",
-            "activation-record",
-            "DeviceCertificate",
-        ],
-    )
-    .unwrap()
-    .as_data()
-    .unwrap();
-
-    Ok(KeyPair {
-        private: private_key.rsa().unwrap().private_key_to_der()?,
-        cert: rustls_pemfile::certs(&mut Cursor::new(certificate.to_vec()))
-            .unwrap()
-            .into_iter()
-            .nth(0)
-            .unwrap(),
-    })
+    let certificate = record
+        .get("DeviceCertificate")
+        .and_then(|v| v.as_data())
+        .ok_or(PushError::AlbertMissingCertificate)?;
+    let cert = rustls_pemfile::certs(&mut Cursor::new(certificate))
+        .map_err(|err| PushError::AlbertMalformedCertificate(err.to_string()))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| PushError::AlbertMalformedCertificate("no PEM certificate".to_string()))?;
+    X509::from_der(&cert)
+        .map_err(|err| PushError::AlbertMalformedCertificate(err.to_string()))?;
+    Ok(cert)
 }
@@ -0,0 +1,383 @@
+use openssl::sha::sha1;
+use tokio::io::{AsyncReadExt, ReadHalf};
+
+use crate::PushError;
+
+use super::transport::APNSStream;
+
+// frames larger than this are treated as corrupt rather than allocated
+const MAX_FRAME_LEN: u32 = 1 << 24;
+
+// magic value sent alongside every state packet
+const STATE_MAGIC: u32 = 0x7FFFFFFF;
+
+// a raw APNs frame: a command byte and a list of (id, value) fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct APNSPayload {
+    pub id: u8,
+    pub fields: Vec<(u8, Vec<u8>)>,
+}
+
+impl APNSPayload {
+    pub fn new(id: u8, fields: Vec<(u8, Vec<u8>)>) -> Self {
+        APNSPayload { id, fields }
+    }
+
+    pub(crate) async fn read(
+        read: &mut ReadHalf<Box<dyn APNSStream>>,
+    ) -> Result<Option<APNSPayload>, PushError> {
+        let id = read.read_u8().await?;
+
+        if id == 0x0 {
+            return Ok(None);
+        }
+
+        let len = read.read_u32().await?;
+        APNSPayload::check_frame_len(len)?;
+        let mut buf = vec![0; len as usize];
+        read.read_exact(&mut buf).await?;
+
+        Ok(Some(APNSPayload {
+            id,
+            fields: APNSPayload::parse_fields(&buf)?,
+        }))
+    }
+
+    // decode exactly one frame, as `serialize` produces
+    pub fn decode(frame: &[u8]) -> Result<APNSPayload, PushError> {
+        let [id, l0, l1, l2, l3, body @ ..] = frame else {
+            return Err(PushError::MalformedAPNSPayload(
+                "truncated frame header".to_string(),
+            ));
+        };
+        let len = u32::from_be_bytes([*l0, *l1, *l2, *l3]);
+        APNSPayload::check_frame_len(len)?;
+        if body.len() != len as usize {
+            return Err(PushError::MalformedAPNSPayload(format!(
+                "frame claims {} bytes, has {}",
+                len,
+                body.len()
+            )));
+        }
+        Ok(APNSPayload {
+            id: *id,
+            fields: APNSPayload::parse_fields(body)?,
+        })
+    }
+
+    fn check_frame_len(len: u32) -> Result<(), PushError> {
+        if len > MAX_FRAME_LEN {
+            return Err(PushError::MalformedAPNSPayload(format!(
+                "frame of {} bytes exceeds limit",
+                len
+            )));
+        }
+        Ok(())
+    }
+
+    fn parse_fields(mut buf: &[u8]) -> Result<Vec<(u8, Vec<u8>)>, PushError> {
+        let mut fields: Vec<(u8, Vec<u8>)> = Vec::new();
+        while !buf.is_empty() {
+            let [fid, len_hi, len_lo, rest @ ..] = buf else {
+                return Err(PushError::MalformedAPNSPayload(
+                    "truncated field header".to_string(),
+                ));
+            };
+            let flen = u16::from_be_bytes([*len_hi, *len_lo]) as usize;
+            if rest.len() < flen {
+                return Err(PushError::MalformedAPNSPayload(format!(
+                    "field {} claims {} bytes, {} left",
+                    fid,
+                    flen,
+                    rest.len()
+                )));
+            }
+            fields.push((*fid, rest[..flen].to_vec()));
+            buf = &rest[flen..];
+        }
+        Ok(fields)
+    }
+
+    pub fn get_field(&self, field: u8) -> Option<&Vec<u8>> {
+        self.fields.iter().find(|f| f.0 == field).map(|i| &i.1)
+    }
+
+    fn required(&self, field: u8) -> Result<&Vec<u8>, PushError> {
+        self.get_field(field).ok_or_else(|| {
+            PushError::MalformedAPNSPayload(format!(
+                "command {:#04x} missing field {}",
+                self.id, field
+            ))
+        })
+    }
+
+    fn required_fixed<const N: usize>(&self, field: u8) -> Result<[u8; N], PushError> {
+        self.required(field)?.as_slice().try_into().map_err(|_| {
+            PushError::MalformedAPNSPayload(format!(
+                "command {:#04x} field {} is not {} bytes",
+                self.id, field, N
+            ))
+        })
+    }
+
+    // fails rather than truncating fields or frames too long to encode
+    pub fn serialize(&self) -> Result<Vec<u8>, PushError> {
+        let mut payload: Vec<u8> = vec![];
+        for (id, val) in &self.fields {
+            let len = u16::try_from(val.len()).map_err(|_| {
+                PushError::MalformedAPNSPayload(format!(
+                    "command {:#04x} field {} is {} bytes, too long to encode",
+                    self.id,
+                    id,
+                    val.len()
+                ))
+            })?;
+            payload.push(*id);
+            payload.extend_from_slice(&len.to_be_bytes());
+            payload.extend_from_slice(val);
+        }
+        let len = u32::try_from(payload.len())
+            .ok()
+            .filter(|len| *len <= MAX_FRAME_LEN)
+            .ok_or_else(|| {
+                PushError::MalformedAPNSPayload(format!(
+                    "command {:#04x} is {} bytes, exceeds limit",
+                    self.id,
+                    payload.len()
+                ))
+            })?;
+        Ok([vec![self.id], len.to_be_bytes().to_vec(), payload].concat())
+    }
+}
+
+// typed APNs commands
+// 0x0A uses different field layouts depending on which side sent it, so client-to-courier
+// messages are `SendMessage` and courier-to-client messages are `Notification`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum APNSCommand {
+    // 0x07
+    Connect {
+        token: Option<Vec<u8>>,
+        state: u8,
+        flags: u32,
+        cert: Vec<u8>,
+        nonce: Vec<u8>,
+        signature: Vec<u8>,
+    },
+    // 0x08
+    ConnectResponse {
+        status: u8,
+        token: Option<Vec<u8>>,
+    },
+    // 0x09, topics are sha1 hashes
+    Filter {
+        token: Vec<u8>,
+        topics: Vec<[u8; 20]>,
+    },
+    // 0x0A, client to courier
+    SendMessage {
+        id: [u8; 4],
+        topic: [u8; 20],
+        token: Vec<u8>,
+        payload: Vec<u8>,
+    },
+    // 0x0A, courier to client
+    Notification {
+        id: [u8; 4],
+        topic: [u8; 20],
+        token: Vec<u8>,
+        payload: Vec<u8>,
+    },
+    // 0x0B
+    Ack {
+        token: Option<Vec<u8>>,
+        id: [u8; 4],
+        status: u8,
+    },
+    // 0x0C
+    KeepAlive,
+    // 0x0D
+    KeepAliveAck,
+    // 0x14
+    SetState {
+        state: u8,
+    },
+    // anything we don't model yet
+    Unknown(APNSPayload),
+}
+
+impl APNSCommand {
+    pub fn topic_hash(topic: &str) -> [u8; 20] {
+        sha1(topic.as_bytes())
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            APNSCommand::Connect { .. } => 0x07,
+            APNSCommand::ConnectResponse { .. } => 0x08,
+            APNSCommand::Filter { .. } => 0x09,
+            APNSCommand::SendMessage { .. } | APNSCommand::Notification { .. } => 0x0A,
+            APNSCommand::Ack { .. } => 0x0B,
+            APNSCommand::KeepAlive => 0x0C,
+            APNSCommand::KeepAliveAck => 0x0D,
+            APNSCommand::SetState { .. } => 0x14,
+            APNSCommand::Unknown(payload) => payload.id,
+        }
+    }
+
+    pub fn to_payload(&self) -> APNSPayload {
+        let fields = match self {
+            APNSCommand::Connect {
+                token,
+                state,
+                flags,
+                cert,
+                nonce,
+                signature,
+            } => {
+                let mut fields = vec![
+                    (0x2, vec![*state]),
+                    (0x5, flags.to_be_bytes().to_vec()),
+                    (0xC, cert.clone()),
+                    (0xD, nonce.clone()),
+                    (0xE, signature.clone()),
+                ];
+                if let Some(token) = token {
+                    fields.push((1, token.clone()));
+                }
+                fields
+            }
+            APNSCommand::ConnectResponse { status, token } => {
+                let mut fields = vec![(1, vec![*status])];
+                if let Some(token) = token {
+                    fields.push((3, token.clone()));
+                }
+                fields
+            }
+            APNSCommand::Filter { token, topics } => [(1, token.clone())]
+                .into_iter()
+                .chain(topics.iter().map(|topic| (2, topic.to_vec())))
+                .collect(),
+            APNSCommand::SendMessage {
+                id,
+                topic,
+                token,
+                payload,
+            } => vec![
+                (4, id.to_vec()),
+                (1, topic.to_vec()),
+                (2, token.clone()),
+                (3, payload.clone()),
+            ],
+            APNSCommand::Notification {
+                id,
+                topic,
+                token,
+                payload,
+            } => vec![
+                (1, token.clone()),
+                (2, topic.to_vec()),
+                (3, payload.clone()),
+                (4, id.to_vec()),
+            ],
+            APNSCommand::Ack { token, id, status } => {
+                let mut fields = vec![];
+                if let Some(token) = token {
+                    fields.push((1, token.clone()));
+                }
+                fields.push((4, id.to_vec()));
+                fields.push((8, vec![*status]));
+                fields
+            }
+            APNSCommand::KeepAlive | APNSCommand::KeepAliveAck => vec![],
+            APNSCommand::SetState { state } => {
+                vec![(1, vec![*state]), (2, STATE_MAGIC.to_be_bytes().to_vec())]
+            }
+            APNSCommand::Unknown(payload) => return payload.clone(),
+        };
+        APNSPayload::new(self.id(), fields)
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, PushError> {
+        self.to_payload().serialize()
+    }
+
+    // decode a frame the courier sent us
+    pub fn decode_incoming(payload: &APNSPayload) -> Result<APNSCommand, PushError> {
+        if payload.id == 0x0A {
+            return Ok(APNSCommand::Notification {
+                id: payload.required_fixed(4)?,
+                topic: payload.required_fixed(2)?,
+                token: payload.required(1)?.clone(),
+                payload: payload.required(3)?.clone(),
+            });
+        }
+        APNSCommand::decode_common(payload)
+    }
+
+    // decode a frame a client sent, for courier implementations
+    pub fn decode_outgoing(payload: &APNSPayload) -> Result<APNSCommand, PushError> {
+        if payload.id == 0x0A {
+            return Ok(APNSCommand::SendMessage {
+                id: payload.required_fixed(4)?,
+                topic: payload.required_fixed(1)?,
+                token: payload.required(2)?.clone(),
+                payload: payload.required(3)?.clone(),
+            });
+        }
+        APNSCommand::decode_common(payload)
+    }
+
+    fn decode_common(payload: &APNSPayload) -> Result<APNSCommand, PushError> {
+        Ok(match payload.id {
+            0x07 => {
+                let [state] = payload.required_fixed(0x2)?;
+                APNSCommand::Connect {
+                    token: payload.get_field(1).cloned(),
+                    state,
+                    flags: u32::from_be_bytes(payload.required_fixed(0x5)?),
+                    cert: payload.required(0xC)?.clone(),
+                    nonce: payload.required(0xD)?.clone(),
+                    signature: payload.required(0xE)?.clone(),
+                }
+            }
+            0x08 => {
+                let [status] = payload.required_fixed(1)?;
+                APNSCommand::ConnectResponse {
+                    status,
+                    token: payload.get_field(3).cloned(),
+                }
+            }
+            0x09 => APNSCommand::Filter {
+                token: payload.required(1)?.clone(),
+                topics: payload
+                    .fields
+                    .iter()
+                    .filter(|(id, _)| *id == 2)
+                    .map(|(_, topic)| {
+                        topic.as_slice().try_into().map_err(|_| {
+                            PushError::MalformedAPNSPayload(
+                                "filter topic is not a sha1 hash".to_string(),
+                            )
+                        })
+                    })
+                    .collect::<Result<Vec<[u8; 20]>, PushError>>()?,
+            },
+            0x0B => {
+                let [status] = payload.required_fixed(8)?;
+                APNSCommand::Ack {
+                    token: payload.get_field(1).cloned(),
+                    id: payload.required_fixed(4)?,
+                    status,
+                }
+            }
+            0x0C => APNSCommand::KeepAlive,
+            0x0D => APNSCommand::KeepAliveAck,
+            0x14 => {
+                let [state] = payload.required_fixed(1)?;
+                APNSCommand::SetState { state }
+            }
+            _ => APNSCommand::Unknown(payload.clone()),
+        })
+    }
+}
@@ -0,0 +1,1280 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, info, warn};
+use openssl::{hash::MessageDigest, pkey::PKey, rsa::Padding, sign::Signer};
+use plist::Value;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::io::split;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Instant};
+use tokio::{
+    io::{AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::{
+        mpsc::{self, UnboundedReceiver},
+        oneshot, watch, Mutex, Notify,
+    },
+};
+
+use crate::{
+    albert::generate_push_cert,
+    ids::signing::generate_nonce,
+    util::{cert_validity, KeyPair},
+    PushError,
+};
+
+use super::{
+    command::{APNSCommand, APNSPayload},
+    queue::{queue, APNSReceiver, QueueSender, SubscriberOptions},
+    transport::{APNSStream, APNSTransport, CourierTransport},
+};
+
+struct InnerSubmitter {
+    stream: WriteHalf<Box<dyn APNSStream>>,
+    token: Vec<u8>,
+    // filtered topics and how many subscriptions hold each
+    topics: Vec<(String, usize)>,
+    // unacked messages, resent once a reconnect finishes
+    pending: Vec<PendingMessage>,
+    // false between losing the connection and finishing init_conn
+    ready: bool,
+}
+
+struct PendingMessage {
+    id: [u8; 4],
+    topic: [u8; 20],
+    payload: Vec<u8>,
+}
+
+impl PendingMessage {
+    fn command(&self, token: &[u8]) -> APNSCommand {
+        APNSCommand::SendMessage {
+            id: self.id,
+            topic: self.topic,
+            token: token.to_vec(),
+            payload: self.payload.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct APNSSubmitter(Arc<Mutex<InnerSubmitter>>, Option<APNSReader>);
+
+impl APNSSubmitter {
+    fn make(stream: WriteHalf<Box<dyn APNSStream>>) -> APNSSubmitter {
+        APNSSubmitter(
+            Arc::new(Mutex::new(InnerSubmitter {
+                stream,
+                token: vec![],
+                topics: vec![],
+                pending: vec![],
+                ready: false,
+            })),
+            None,
+        )
+    }
+
+    async fn token(&self) -> Vec<u8> {
+        let locked = self.0.lock().await;
+        locked.token.clone()
+    }
+
+    async fn set_token(&self, token: &[u8]) {
+        let mut locked = self.0.lock().await;
+        locked.token = token.to_vec();
+    }
+
+    // returns true if the topic was not filtered before
+    async fn add_topic(&self, topic: &str) -> bool {
+        let mut locked = self.0.lock().await;
+        if let Some((_, count)) = locked.topics.iter_mut().find(|(t, _)| t == topic) {
+            *count += 1;
+            return false;
+        }
+        locked.topics.push((topic.to_string(), 1));
+        true
+    }
+
+    // returns true if nothing is subscribed to the topic anymore
+    async fn remove_topic(&self, topic: &str) -> bool {
+        let mut locked = self.0.lock().await;
+        let Some(idx) = locked.topics.iter().position(|(t, _)| t == topic) else {
+            return false;
+        };
+        locked.topics[idx].1 -= 1;
+        if locked.topics[idx].1 > 0 {
+            return false;
+        }
+        locked.topics.remove(idx);
+        true
+    }
+
+    // final state packet, then close the stream (and TLS session) cleanly
+    async fn close(&self) -> Result<(), PushError> {
+        let mut locked = self.0.lock().await;
+        debug!("Sending final state packet");
+        let result = locked
+            .stream
+            .write_all(&APNSCommand::SetState { state: 0 }.serialize()?)
+            .await;
+        locked.stream.shutdown().await?;
+        Ok(result?)
+    }
+
+    async fn write_data(&self, buf: &[u8]) -> Result<(), PushError> {
+        let mut locked = self.0.lock().await;
+        locked.stream.write_all(buf).await?;
+        Ok(())
+    }
+
+    async fn send_command(&self, command: APNSCommand) -> Result<(), PushError> {
+        //debug!("Sending command {:?}", command);
+        self.write_data(&command.serialize()?).await?;
+        Ok(())
+    }
+
+    pub async fn set_state(&self, state: u8) -> Result<(), PushError> {
+        debug!("Sending state packet {}", state);
+        self.send_command(APNSCommand::SetState { state }).await?;
+        Ok(())
+    }
+
+    // queues the message until acked; while reconnecting it is only queued
+    async fn send_message(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        id: [u8; 4],
+    ) -> Result<(), PushError> {
+        let mut locked = self.0.lock().await;
+        if locked.pending.iter().any(|message| message.id == id) {
+            debug!("message {:?} is already queued", id);
+            return Ok(());
+        }
+        let message = PendingMessage {
+            id,
+            topic: APNSCommand::topic_hash(topic),
+            payload: payload.to_vec(),
+        };
+        // too long to send is the caller's error, not something to retry
+        let data = message.command(&locked.token).serialize()?;
+        locked.pending.push(message);
+        if !locked.ready {
+            debug!("queued message {:?} until reconnected", id);
+            return Ok(());
+        }
+        if let Err(err) = locked.stream.write_all(&data).await {
+            warn!(
+                "failed to send {:?}, will retry after reconnect: {:?}",
+                id, err
+            );
+        }
+        Ok(())
+    }
+
+    // acked, or the sender gave up on it
+    async fn forget_message(&self, id: [u8; 4]) {
+        let mut locked = self.0.lock().await;
+        locked.pending.retain(|message| message.id != id);
+    }
+
+    async fn mark_disconnected(&self) {
+        self.0.lock().await.ready = false;
+    }
+
+    // resend everything unacked, then let new messages through
+    async fn flush_pending(&self) -> Result<(), PushError> {
+        let mut locked = self.0.lock().await;
+        let InnerSubmitter {
+            stream,
+            token,
+            pending,
+            ready,
+            ..
+        } = &mut *locked;
+        for message in pending.iter() {
+            debug!("resending message {:?}", message.id);
+            stream
+                .write_all(&message.command(token).serialize()?)
+                .await?;
+        }
+        *ready = true;
+        Ok(())
+    }
+
+    async fn keep_alive(&self) -> Result<(), PushError> {
+        self.send_command(APNSCommand::KeepAlive).await?;
+        debug!("Sending keep alive");
+        Ok(())
+    }
+
+    async fn send_ack(&self, id: [u8; 4]) -> Result<(), PushError> {
+        debug!("Sending ack for {:?}", id);
+        self.send_command(APNSCommand::Ack {
+            token: Some(self.token().await),
+            id,
+            status: 0x0,
+        })
+        .await?;
+        Ok(())
+    }
+
+    // filters on every subscribed topic
+    pub async fn filter(&self) -> Result<(), PushError> {
+        let locked = self.0.lock().await;
+        let topics: Vec<String> = locked.topics.iter().map(|(t, _)| t.clone()).collect();
+        let token = locked.token.clone();
+        drop(locked);
+        debug!("Sending filter for {:?}", topics);
+        self.send_command(APNSCommand::Filter {
+            token,
+            topics: topics
+                .iter()
+                .map(|topic| APNSCommand::topic_hash(topic))
+                .collect(),
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+enum WaitingCb {
+    OneShot(oneshot::Sender<APNSCommand>),
+    // shared so the read loop can push without holding `waiting`
+    Cont(Arc<QueueSender>),
+}
+
+impl WaitingCb {
+    fn is_closed(&self) -> bool {
+        match self {
+            WaitingCb::OneShot(cb) => cb.is_closed(),
+            WaitingCb::Cont(cb) => cb.is_closed(),
+        }
+    }
+}
+
+struct WaitingTask {
+    id: u64,
+    waiting_for: Box<dyn Fn(&APNSCommand) -> bool + Send + Sync>,
+    when: WaitingCb,
+}
+
+// health of the courier connection, published whenever it changes
+#[derive(Debug, Clone, PartialEq)]
+pub enum APNSConnectionState {
+    Connecting,
+    Connected { token: Vec<u8> },
+    Disconnected { reason: String },
+    // waiting before reconnect attempt `retry`
+    Backoff { retry: u64 },
+    // `shutdown` was called or the connection was dropped, we won't reconnect
+    Shutdown,
+}
+
+// how often we ping the courier, and when we give up on it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeepAlivePolicy {
+    pub interval: Duration,
+    // how long to wait for the 0x0D reply to each ping
+    pub timeout: Duration,
+    // missed replies in a row before the connection is considered dead
+    pub max_missed: u32,
+}
+
+impl Default for KeepAlivePolicy {
+    fn default() -> Self {
+        KeepAlivePolicy {
+            interval: Duration::from_secs(300),
+            timeout: Duration::from_secs(30),
+            max_missed: 2,
+        }
+    }
+}
+
+#[derive(Default)]
+struct BackgroundTasks {
+    reader: Option<JoinHandle<()>>,
+    keep_alive: Option<JoinHandle<()>>,
+    // finishes a reconnect once the new stream is open
+    init: Option<JoinHandle<()>>,
+    cert_renewal: Option<JoinHandle<()>>,
+    closed: bool,
+}
+
+#[derive(Clone)]
+pub struct APNSReader {
+    waiting: Arc<Mutex<Vec<WaitingTask>>>,
+    next_wait_id: Arc<AtomicU64>,
+    conn_state: Arc<watch::Sender<APNSConnectionState>>,
+    // std mutex so tasks can be aborted from Drop
+    tasks: Arc<StdMutex<BackgroundTasks>>,
+    keep_alive_policy: Arc<watch::Sender<KeepAlivePolicy>>,
+    // wakes the read loop to drop the current connection and make a new one
+    force_reconnect: Arc<Notify>,
+    reconnect_reason: Arc<StdMutex<String>>,
+}
+
+impl APNSReader {
+    fn set_conn_state(&self, state: APNSConnectionState) {
+        if state != APNSConnectionState::Shutdown && self.tasks.lock().unwrap().closed {
+            return;
+        }
+        debug!("APNs connection state {:?}", state);
+        self.conn_state.send_replace(state);
+    }
+
+    // replaces the task in `slot` from the previous connection, if any
+    fn set_task(
+        &self,
+        slot: fn(&mut BackgroundTasks) -> &mut Option<JoinHandle<()>>,
+        handle: JoinHandle<()>,
+    ) {
+        let mut tasks = self.tasks.lock().unwrap();
+        if tasks.closed {
+            handle.abort();
+            return;
+        }
+        if let Some(old) = slot(&mut tasks).replace(handle) {
+            old.abort();
+        }
+    }
+
+    fn set_keep_alive(&self, handle: JoinHandle<()>) {
+        self.set_task(|tasks| &mut tasks.keep_alive, handle);
+    }
+
+    // no-op unless connected, a connection in progress already uses the latest state
+    fn request_reconnect(&self, reason: &str) {
+        if !matches!(
+            *self.conn_state.borrow(),
+            APNSConnectionState::Connected { .. }
+        ) {
+            return;
+        }
+        *self.reconnect_reason.lock().unwrap() = reason.to_string();
+        self.force_reconnect.notify_one();
+    }
+
+    // keep-alives and any unfinished connect belong to the connection that just dropped
+    fn stop_connection_tasks(&self) {
+        let mut tasks = self.tasks.lock().unwrap();
+        for handle in [tasks.keep_alive.take(), tasks.init.take()]
+            .into_iter()
+            .flatten()
+        {
+            handle.abort();
+        }
+    }
+
+    async fn keep_alive_loop(self, write: APNSSubmitter) {
+        let mut missed = 0;
+        let mut policy_changes = self.keep_alive_policy.subscribe();
+        loop {
+            let policy = *policy_changes.borrow_and_update();
+            tokio::select! {
+                _ = sleep(policy.interval) => {}
+                // start over with the new interval
+                _ = policy_changes.changed() => continue,
+            }
+            let (wait_id, rx) = self
+                .register_once(|item| matches!(item, APNSCommand::KeepAliveAck))
+                .await;
+            if let Err(err) = write.keep_alive().await {
+                warn!("failed to send keep-alive {:?}", err);
+            }
+            match self.finish_wait(wait_id, rx, Some(policy.timeout)).await {
+                Ok(_) => missed = 0,
+                Err(PushError::APNSTimeout) => {
+                    missed += 1;
+                    warn!(
+                        "missed APNs keep-alive reply ({}/{})",
+                        missed, policy.max_missed
+                    );
+                    if missed >= policy.max_missed {
+                        self.request_reconnect("APNs stopped answering keep-alives");
+                        return;
+                    }
+                }
+                Err(_) => return,
+            }
+        }
+    }
+
+    // returns false if the tasks were already stopped
+    fn abort_tasks(&self) -> bool {
+        let mut tasks = self.tasks.lock().unwrap();
+        if tasks.closed {
+            return false;
+        }
+        tasks.closed = true;
+        for handle in [
+            tasks.reader.take(),
+            tasks.keep_alive.take(),
+            tasks.init.take(),
+            tasks.cert_renewal.take(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            handle.abort();
+        }
+        true
+    }
+
+    // reads until the connection drops, then reconnects, until the task is aborted
+    async fn run(
+        self,
+        transport: Arc<dyn APNSTransport>,
+        mut read: ReadHalf<Box<dyn APNSStream>>,
+        write: APNSSubmitter,
+        state: SharedState,
+    ) {
+        // attempts since the courier last accepted us
+        let mut retry = 0;
+        // the first connection is finished by `new_with_transport`, which gives up on failure
+        let (_, mut init_failed) = mpsc::unbounded_channel();
+        loop {
+            let reason = self
+                .read_connection(&mut read, &write, &mut init_failed)
+                .await;
+            self.stop_connection_tasks();
+            // a courier that takes the socket and then hangs up still gets backed off from
+            if matches!(
+                *self.conn_state.borrow(),
+                APNSConnectionState::Connected { .. }
+            ) {
+                retry = 0;
+            } else {
+                retry += 1;
+            }
+            self.set_conn_state(APNSConnectionState::Disconnected { reason });
+            (read, init_failed) = self.reconnect(&transport, &write, &state, &mut retry).await;
+        }
+    }
+
+    // backs off until a new stream is open, then finishes connecting in the background;
+    // if that fails, the reason comes through the returned receiver
+    async fn reconnect(
+        &self,
+        transport: &Arc<dyn APNSTransport>,
+        write: &APNSSubmitter,
+        state: &SharedState,
+        retry: &mut u64,
+    ) -> (ReadHalf<Box<dyn APNSStream>>, UnboundedReceiver<String>) {
+        info!("attempting to reconnect to APNs!");
+        write.mark_disconnected().await;
+        let stream = loop {
+            self.set_conn_state(APNSConnectionState::Backoff { retry: *retry });
+            sleep(Duration::from_secs(std::cmp::min(10 * *retry, 30))).await;
+            self.set_conn_state(APNSConnectionState::Connecting);
+            match transport.connect().await {
+                Ok(stream) => break stream,
+                Err(err) => {
+                    warn!("failed to reconnect to APNs! {:?}", err);
+                    self.set_conn_state(APNSConnectionState::Disconnected {
+                        reason: format!("{:?}", err),
+                    });
+                    *retry += 1;
+                }
+            }
+        };
+        let (read, writer) = split(stream);
+        let mut write_half = write.0.lock().await;
+        write_half.stream = writer;
+        drop(write_half);
+        let self2 = self.clone();
+        let state2 = state.clone();
+        let write2 = write.clone();
+        let (failed, init_failed) = mpsc::unbounded_channel();
+        let init = tokio::spawn(async move {
+            let mut new_state = state2.borrow().clone();
+            match APNSConnection::init_conn(&write2, &self2, &mut new_state).await {
+                Ok(token) => {
+                    // the push cert was replaced while we were connecting
+                    let stale = state2.borrow().keypair.cert != new_state.keypair.cert;
+                    state2.send_if_modified(|current| {
+                        if stale || current.token == new_state.token {
+                            return false;
+                        }
+                        info!("APNs assigned a new push token");
+                        *current = new_state;
+                        true
+                    });
+                    self2.set_conn_state(APNSConnectionState::Connected { token });
+                    if stale {
+                        self2.request_reconnect("push certificate changed");
+                    }
+                }
+                Err(err) => {
+                    warn!("failed to conenct to APNs: {:?}", err);
+                    // the read loop drops this stream and backs off
+                    let _ = failed.send(format!("{:?}", err));
+                }
+            }
+        });
+        self.set_task(|tasks| &mut tasks.init, init);
+        (read, init_failed)
+    }
+
+    // dispatches payloads until the connection breaks, returning why it did
+    async fn read_connection(
+        &self,
+        read: &mut ReadHalf<Box<dyn APNSStream>>,
+        write: &APNSSubmitter,
+        init_failed: &mut UnboundedReceiver<String>,
+    ) -> String {
+        loop {
+            let result = tokio::select! {
+                result = APNSPayload::read(read) => result.map_err(|err| {
+                    warn!("conn broken? {:?}", err);
+                    format!("{:?}", err)
+                }),
+                _ = self.force_reconnect.notified() => {
+                    let reason = self.reconnect_reason.lock().unwrap().clone();
+                    warn!("dropping APNs connection: {}", reason);
+                    Err(reason)
+                }
+                Some(reason) = init_failed.recv() => Err(reason),
+            };
+            let payload = match result {
+                Ok(payload) => payload,
+                Err(reason) => return reason,
+            };
+            let Some(payload) = payload else { continue };
+            let payload = match APNSCommand::decode_incoming(&payload) {
+                Ok(command) => command,
+                Err(err) => {
+                    warn!("dropping malformed payload {:?}", err);
+                    continue;
+                }
+            };
+            if let APNSCommand::Notification { id, .. } = &payload {
+                debug!("Sending automatic ACK");
+                if let Err(err) = write.send_ack(*id).await {
+                    warn!("failed to ack, conn broken? {:?}", err);
+                    return format!("{:?}", err);
+                }
+            }
+
+            //debug!("Recieved payload {:?}", payload);
+            if let APNSCommand::Ack { id, .. } = &payload {
+                write.forget_message(*id).await;
+            }
+
+            let subscribers = self.dispatch(&payload).await;
+            // pushing can wait under `OverflowPolicy::Block`, so it happens without the lock;
+            // otherwise a stuck subscriber would also hold up sends, acks and keep-alives
+            let mut gone = vec![];
+            for (id, cb) in subscribers {
+                if !cb.push(payload.clone()).await {
+                    // subscriber went away or overflowed
+                    gone.push(id);
+                }
+            }
+            if !gone.is_empty() {
+                self.waiting
+                    .lock()
+                    .await
+                    .retain(|task| !gone.contains(&task.id));
+            }
+        }
+    }
+
+    // answers one-shot waiters for `payload`, returning the subscribers it should be pushed to
+    async fn dispatch(&self, payload: &APNSCommand) -> Vec<(u64, Arc<QueueSender>)> {
+        let mut locked = self.waiting.lock().await;
+        // waiters that timed out or were cancelled
+        locked.retain(|task| !task.when.is_closed());
+        let mut subscribers = vec![];
+        let mut idx = 0;
+        while idx < locked.len() {
+            if !(locked[idx].waiting_for)(payload) {
+                idx += 1;
+                continue;
+            }
+            match &locked[idx].when {
+                WaitingCb::OneShot(_) => {
+                    let WaitingCb::OneShot(cb) = locked.remove(idx).when else {
+                        unreachable!()
+                    };
+                    let _ = cb.send(payload.clone());
+                }
+                WaitingCb::Cont(cb) => {
+                    subscribers.push((locked[idx].id, cb.clone()));
+                    idx += 1;
+                }
+            }
+        }
+        subscribers
+    }
+
+    fn new(
+        transport: Arc<dyn APNSTransport>,
+        read: ReadHalf<Box<dyn APNSStream>>,
+        write: APNSSubmitter,
+        state: SharedState,
+    ) -> APNSReader {
+        let reader = APNSReader {
+            waiting: Arc::new(Mutex::new(vec![])),
+            next_wait_id: Arc::new(AtomicU64::new(0)),
+            conn_state: Arc::new(watch::channel(APNSConnectionState::Connecting).0),
+            tasks: Arc::new(StdMutex::new(BackgroundTasks::default())),
+            keep_alive_policy: Arc::new(watch::channel(KeepAlivePolicy::default()).0),
+            force_reconnect: Arc::new(Notify::new()),
+            reconnect_reason: Arc::new(StdMutex::new(String::new())),
+        };
+        let reader_clone = reader.clone();
+        let handle = tokio::spawn(async move {
+            reader_clone.run(transport, read, write, state).await;
+        });
+        reader.tasks.lock().unwrap().reader = Some(handle);
+        reader
+    }
+
+    pub async fn register_for<F>(&self, p: F) -> APNSReceiver
+    where
+        F: Fn(&APNSCommand) -> bool + Send + Sync + 'static,
+    {
+        self.register_for_with(SubscriberOptions::default(), p)
+            .await
+    }
+
+    // each subscriber gets its own buffer, so a slow one only affects itself
+    pub async fn register_for_with<F>(&self, options: SubscriberOptions, p: F) -> APNSReceiver
+    where
+        F: Fn(&APNSCommand) -> bool + Send + Sync + 'static,
+    {
+        let mut locked = self.waiting.lock().await;
+        let (tx, rx) = queue(options);
+        locked.push(WaitingTask {
+            id: self.next_wait_id.fetch_add(1, Ordering::Relaxed),
+            waiting_for: Box::new(p),
+            when: WaitingCb::Cont(Arc::new(tx)),
+        });
+        rx
+    }
+
+    // register before sending whatever triggers the reply, so it can't be missed
+    async fn register_once<F>(&self, p: F) -> (u64, oneshot::Receiver<APNSCommand>)
+    where
+        F: Fn(&APNSCommand) -> bool + Send + Sync + 'static,
+    {
+        let mut locked = self.waiting.lock().await;
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_wait_id.fetch_add(1, Ordering::Relaxed);
+        locked.push(WaitingTask {
+            id,
+            waiting_for: Box::new(p),
+            when: WaitingCb::OneShot(tx),
+        });
+        (id, rx)
+    }
+
+    async fn finish_wait(
+        &self,
+        id: u64,
+        rx: oneshot::Receiver<APNSCommand>,
+        wait: Option<Duration>,
+    ) -> Result<APNSCommand, PushError> {
+        let Some(wait) = wait else {
+            return rx.await.map_err(|_| PushError::APNSConnectionClosed);
+        };
+        match timeout(wait, rx).await {
+            Ok(result) => result.map_err(|_| PushError::APNSConnectionClosed),
+            Err(_) => {
+                self.waiting.lock().await.retain(|task| task.id != id);
+                Err(PushError::APNSTimeout)
+            }
+        }
+    }
+
+    // like `finish_wait`, but the clock only runs while connected, so something queued during
+    // a slow reconnect still gets all of `wait` once it has actually been sent
+    async fn finish_wait_connected(
+        &self,
+        id: u64,
+        mut rx: oneshot::Receiver<APNSCommand>,
+        wait: Duration,
+    ) -> Result<APNSCommand, PushError> {
+        let mut conn_state = self.conn_state.subscribe();
+        let mut remaining = wait;
+        loop {
+            let connected = match &*conn_state.borrow_and_update() {
+                APNSConnectionState::Shutdown => return Err(PushError::APNSConnectionClosed),
+                APNSConnectionState::Connected { .. } => true,
+                _ => false,
+            };
+            let started = Instant::now();
+            let clock = async move {
+                if connected {
+                    sleep(remaining).await
+                } else {
+                    std::future::pending().await
+                }
+            };
+            tokio::select! {
+                result = &mut rx => {
+                    return result.map_err(|_| PushError::APNSConnectionClosed)
+                }
+                _ = clock => {
+                    self.waiting.lock().await.retain(|task| task.id != id);
+                    return Err(PushError::APNSTimeout);
+                }
+                changed = conn_state.changed() => {
+                    if changed.is_err() {
+                        return Err(PushError::APNSConnectionClosed);
+                    }
+                    if connected {
+                        remaining = remaining.saturating_sub(started.elapsed());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn wait_for<F>(&self, p: F, wait: Option<Duration>) -> Result<APNSCommand, PushError>
+    where
+        F: Fn(&APNSCommand) -> bool + Send + Sync + 'static,
+    {
+        let (id, rx) = self.register_once(p).await;
+        self.finish_wait(id, rx, wait).await
+    }
+
+    // matches notifications whose plist body matches `p`
+    fn msg_predicate<F>(p: F) -> impl Fn(&APNSCommand) -> bool + Send + Sync + 'static
+    where
+        F: Fn(&Value) -> bool + Send + Sync + 'static,
+    {
+        move |x| {
+            let APNSCommand::Notification { payload, .. } = x else {
+                return false;
+            };
+            let Ok(loaded) = plist::from_bytes::<Value>(payload) else {
+                return false;
+            };
+            p(&loaded)
+        }
+    }
+
+    fn msg_body(found: APNSCommand) -> Result<Value, PushError> {
+        let APNSCommand::Notification { payload, .. } = found else {
+            unreachable!("predicate only matches notifications")
+        };
+        Ok(plist::from_bytes(&payload)?)
+    }
+
+    async fn wait_for_msg<F>(&self, p: F, wait: Option<Duration>) -> Result<Value, PushError>
+    where
+        F: Fn(&Value) -> bool + Send + Sync + 'static,
+    {
+        let found = self.wait_for(APNSReader::msg_predicate(p), wait).await?;
+        APNSReader::msg_body(found)
+    }
+
+    // waits for a notification whose plist body matches, returning that body
+    pub async fn wait_find_msg<F>(&self, p: F) -> Result<Value, PushError>
+    where
+        F: Fn(&Value) -> bool + Send + Sync + 'static,
+    {
+        self.wait_for_msg(p, None).await
+    }
+
+    pub async fn wait_find_msg_timeout<F>(&self, p: F, wait: Duration) -> Result<Value, PushError>
+    where
+        F: Fn(&Value) -> bool + Send + Sync + 'static,
+    {
+        self.wait_for_msg(p, Some(wait)).await
+    }
+
+    pub async fn wait_find_pred<F>(&self, p: F) -> Result<APNSCommand, PushError>
+    where
+        F: Fn(&APNSCommand) -> bool + Send + Sync + 'static,
+    {
+        self.wait_for(p, None).await
+    }
+
+    pub async fn wait_find_pred_timeout<F>(
+        &self,
+        p: F,
+        wait: Duration,
+    ) -> Result<APNSCommand, PushError>
+    where
+        F: Fn(&APNSCommand) -> bool + Send + Sync + 'static,
+    {
+        self.wait_for(p, Some(wait)).await
+    }
+
+    pub async fn wait_find(&self, id: u8) -> Result<APNSCommand, PushError> {
+        self.wait_for(move |item| item.id() == id, None).await
+    }
+
+    pub async fn wait_find_timeout(
+        &self,
+        id: u8,
+        wait: Duration,
+    ) -> Result<APNSCommand, PushError> {
+        self.wait_for(move |item| item.id() == id, Some(wait)).await
+    }
+}
+
+// notifications for a single topic, from `APNSConnection::subscribe`. dropping it releases the
+// topic in the background; `APNSConnection::unsubscribe` does the same and waits for the filter
+pub struct APNSSubscription {
+    topic: String,
+    receiver: APNSReceiver,
+    // None once the topic has been released
+    submitter: Option<APNSSubmitter>,
+}
+
+impl Drop for APNSSubscription {
+    fn drop(&mut self) {
+        let Some(submitter) = self.submitter.take() else {
+            return;
+        };
+        // the submitter's lock is async, so this has to happen on a task
+        let Ok(runtime) = Handle::try_current() else {
+            warn!("subscription to {} dropped outside a runtime", self.topic);
+            return;
+        };
+        let topic = std::mem::take(&mut self.topic);
+        runtime.spawn(async move {
+            if submitter.remove_topic(&topic).await {
+                if let Err(err) = submitter.filter().await {
+                    // the next connection filters without it anyway
+                    debug!("failed to stop filtering on {}: {:?}", topic, err);
+                }
+            }
+        });
+    }
+}
+
+impl APNSSubscription {
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub async fn recv(&mut self) -> Result<APNSCommand, PushError> {
+        self.receiver.recv().await
+    }
+
+    pub fn try_recv(&mut self) -> Result<Option<APNSCommand>, PushError> {
+        self.receiver.try_recv()
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.receiver.dropped()
+    }
+}
+
+pub struct APNSConnection {
+    pub submitter: APNSSubmitter,
+    pub reader: APNSReader,
+    state: SharedState,
+}
+
+type SharedState = Arc<watch::Sender<APNSState>>;
+
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const CERT_RENEWAL_RETRY: Duration = Duration::from_secs(60 * 60);
+
+// tasks are stopped, but only `shutdown` closes the connection cleanly
+impl Drop for APNSConnection {
+    fn drop(&mut self) {
+        if self.reader.abort_tasks() {
+            self.reader.set_conn_state(APNSConnectionState::Shutdown);
+        }
+    }
+}
+
+// serialize this to JSON to save state
+#[derive(Serialize, Deserialize, Clone)]
+pub struct APNSState {
+    pub keypair: KeyPair,
+    pub token: Option<Vec<u8>>,
+}
+
+impl APNSState {
+    // (not before, not after) of the push certificate
+    pub fn cert_validity(&self) -> Result<(SystemTime, SystemTime), PushError> {
+        cert_validity(&self.keypair.cert)
+    }
+
+    pub fn cert_expiry(&self) -> Result<SystemTime, PushError> {
+        Ok(self.cert_validity()?.1)
+    }
+
+    // true if the push certificate expires within `margin`
+    pub fn needs_renewal(&self, margin: Duration) -> Result<bool, PushError> {
+        Ok(self.cert_expiry()? <= SystemTime::now() + margin)
+    }
+}
+
+impl APNSConnection {
+    // sends and waits for the courier to ack this message id
+    pub async fn send_message(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        id: Option<[u8; 4]>,
+    ) -> Result<(), PushError> {
+        self.send_message_timeout(topic, payload, id, ACK_TIMEOUT)
+            .await
+    }
+
+    pub async fn send_message_timeout(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        id: Option<[u8; 4]>,
+        wait: Duration,
+    ) -> Result<(), PushError> {
+        let id = id.unwrap_or_else(|| rand::thread_rng().gen());
+        let (wait_id, rx) = self
+            .reader
+            .register_once(
+                move |item| matches!(item, APNSCommand::Ack { id: acked, .. } if acked == &id),
+            )
+            .await;
+        self.submitter.send_message(topic, payload, id).await?;
+        let result = self.reader.finish_wait_connected(wait_id, rx, wait).await;
+        // don't retry something the caller was told failed
+        self.submitter.forget_message(id).await;
+        let APNSCommand::Ack { status, .. } = result? else {
+            unreachable!("predicate only matches acks")
+        };
+        if status != 0x0 {
+            return Err(PushError::APNSSendError(status));
+        }
+        Ok(())
+    }
+
+    // sends, then waits for the notification whose plist body matches `p`; we listen before
+    // sending, so a reply that arrives ahead of the ack isn't missed
+    pub async fn send_message_for_reply<F>(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        id: Option<[u8; 4]>,
+        p: F,
+    ) -> Result<Value, PushError>
+    where
+        F: Fn(&Value) -> bool + Send + Sync + 'static,
+    {
+        self.send_message_for_reply_timeout(topic, payload, id, p, ACK_TIMEOUT)
+            .await
+    }
+
+    // `wait` is how long the reply may take once the message is acked
+    pub async fn send_message_for_reply_timeout<F>(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        id: Option<[u8; 4]>,
+        p: F,
+        wait: Duration,
+    ) -> Result<Value, PushError>
+    where
+        F: Fn(&Value) -> bool + Send + Sync + 'static,
+    {
+        let (wait_id, rx) = self
+            .reader
+            .register_once(APNSReader::msg_predicate(p))
+            .await;
+        // on failure `rx` is dropped, and the read loop discards the waiter
+        self.send_message(topic, payload, id).await?;
+        let found = self.reader.finish_wait(wait_id, rx, Some(wait)).await?;
+        APNSReader::msg_body(found)
+    }
+
+    // current keypair and token, serialize this to save state
+    pub fn state(&self) -> APNSState {
+        self.state.borrow().clone()
+    }
+
+    // changes whenever a reconnect hands us a new push token, so saved state can be updated
+    pub fn watch_state(&self) -> watch::Receiver<APNSState> {
+        self.state.subscribe()
+    }
+
+    pub fn set_keep_alive_policy(&self, policy: KeepAlivePolicy) {
+        self.reader.keep_alive_policy.send_replace(policy);
+    }
+
+    pub fn connection_state(&self) -> APNSConnectionState {
+        self.reader.conn_state.borrow().clone()
+    }
+
+    // changes whenever the connection drops, backs off or comes back
+    pub fn watch_connection_state(&self) -> watch::Receiver<APNSConnectionState> {
+        self.reader.conn_state.subscribe()
+    }
+
+    // switches to a new push certificate and reconnects with it; the courier
+    // assigns a new token, so anything registered with the old one must be redone
+    pub fn set_push_cert(&self, keypair: KeyPair) {
+        APNSConnection::install_push_cert(&self.reader, &self.state, keypair);
+    }
+
+    fn install_push_cert(reader: &APNSReader, state: &SharedState, keypair: KeyPair) {
+        state.send_modify(|state| {
+            state.keypair = keypair;
+            state.token = None;
+        });
+        reader.request_reconnect("push certificate changed");
+    }
+
+    // fetches a new push certificate from Albert and reconnects with it
+    pub async fn renew_push_cert(&self) -> Result<(), PushError> {
+        info!("renewing push certificate");
+        self.set_push_cert(generate_push_cert().await?);
+        Ok(())
+    }
+
+    // renews if the push certificate expires within `margin`, returns whether it did
+    pub async fn renew_push_cert_if_needed(&self, margin: Duration) -> Result<bool, PushError> {
+        if !self.state().needs_renewal(margin)? {
+            return Ok(false);
+        }
+        self.renew_push_cert().await?;
+        Ok(true)
+    }
+
+    // keeps renewing the push certificate `margin` before it expires, until shutdown
+    pub fn auto_renew_push_cert(&self, margin: Duration) {
+        let handle = tokio::spawn(APNSConnection::cert_renewal_loop(
+            self.reader.clone(),
+            self.state.clone(),
+            margin,
+        ));
+        let mut tasks = self.reader.tasks.lock().unwrap();
+        if tasks.closed {
+            handle.abort();
+            return;
+        }
+        if let Some(old) = tasks.cert_renewal.replace(handle) {
+            old.abort();
+        }
+    }
+
+    async fn cert_renewal_loop(reader: APNSReader, state: SharedState, margin: Duration) {
+        loop {
+            let expiry = state.borrow().cert_expiry();
+            let renew_at = match expiry {
+                Ok(expiry) => expiry.checked_sub(margin).unwrap_or(UNIX_EPOCH),
+                Err(err) => {
+                    warn!("can't read push certificate expiry: {:?}", err);
+                    return;
+                }
+            };
+            if let Ok(wait) = renew_at.duration_since(SystemTime::now()) {
+                debug!("renewing push certificate in {:?}", wait);
+                sleep(wait).await;
+            }
+            match generate_push_cert().await {
+                Ok(keypair) => {
+                    info!("renewed push certificate");
+                    APNSConnection::install_push_cert(&reader, &state, keypair);
+                }
+                Err(err) => {
+                    warn!("failed to renew push certificate: {:?}", err);
+                    sleep(CERT_RENEWAL_RETRY).await;
+                }
+            }
+        }
+    }
+
+    // stops reconnecting and keep-alives, ends every subscription and closes the connection
+    pub async fn shutdown(&self) -> Result<(), PushError> {
+        if !self.reader.abort_tasks() {
+            return Ok(());
+        }
+        info!("shutting down APNs connection");
+        // pending waits fail with APNSConnectionClosed
+        self.reader.waiting.lock().await.clear();
+        let result = self.submitter.close().await;
+        self.reader.set_conn_state(APNSConnectionState::Shutdown);
+        result
+    }
+
+    // receive notifications for `topic`; the filter is re-sent after every reconnect
+    pub async fn subscribe(&self, topic: &str) -> APNSSubscription {
+        self.subscribe_filtered(topic, SubscriberOptions::default(), |_| true)
+            .await
+    }
+
+    // like `subscribe`, but only notifications matching `p` are queued
+    pub async fn subscribe_filtered<F>(
+        &self,
+        topic: &str,
+        options: SubscriberOptions,
+        p: F,
+    ) -> APNSSubscription
+    where
+        F: Fn(&APNSCommand) -> bool + Send + Sync + 'static,
+    {
+        let hash = APNSCommand::topic_hash(topic);
+        let receiver = self
+            .reader
+            .register_for_with(options, move |command| {
+                matches!(command, APNSCommand::Notification { topic, .. } if topic == &hash)
+                    && p(command)
+            })
+            .await;
+        if self.submitter.add_topic(topic).await {
+            if let Err(err) = self.submitter.filter().await {
+                // we'll filter on it once the connection comes back
+                warn!("failed to filter on {}: {:?}", topic, err);
+            }
+        }
+        APNSSubscription {
+            topic: topic.to_string(),
+            receiver,
+            submitter: Some(self.submitter.clone()),
+        }
+    }
+
+    pub async fn unsubscribe(&self, mut subscription: APNSSubscription) -> Result<(), PushError> {
+        // released here instead, so the drop doesn't do it a second time
+        subscription.submitter = None;
+        let topic = subscription.topic.clone();
+        drop(subscription);
+        if self.submitter.remove_topic(&topic).await {
+            self.submitter.filter().await?;
+        }
+        Ok(())
+    }
+
+    async fn init_conn(
+        submitter: &APNSSubmitter,
+        reader: &APNSReader,
+        state: &mut APNSState,
+    ) -> Result<Vec<u8>, PushError> {
+        // connect
+        let flags: u32 = 0b01000001;
+
+        let priv_key = PKey::private_key_from_der(&state.keypair.private)?;
+
+        let mut signer = Signer::new(MessageDigest::sha1(), priv_key.as_ref())?;
+        signer.set_rsa_padding(Padding::PKCS1)?;
+        let nonce = generate_nonce(0x0);
+        let signature = [vec![0x1, 0x1], signer.sign_oneshot_to_vec(&nonce)?].concat();
+
+        if let Some(token) = &state.token {
+            debug!("Sending connect message with token {:?}", token);
+        } else {
+            debug!("Sending connect message without token");
+        }
+
+        let (wait_id, rx) = reader.register_once(|item| item.id() == 0x08).await;
+        submitter
+            .send_command(APNSCommand::Connect {
+                token: state.token.clone(),
+                state: 0x01,
+                flags,
+                cert: state.keypair.cert.clone(),
+                nonce,
+                signature,
+            })
+            .await?;
+
+        let APNSCommand::ConnectResponse {
+            status,
+            token: new_token,
+        } = reader
+            .finish_wait(wait_id, rx, Some(CONNECT_TIMEOUT))
+            .await?
+        else {
+            unreachable!("0x08 is always a connect response")
+        };
+        if status != 0x00 {
+            return Err(PushError::APNSConnectError);
+        }
+
+        if let Some(new_token) = new_token {
+            state.token = Some(new_token);
+        }
+        // a renewed cert has no token yet, so the courier must assign one
+        let Some(token) = state.token.clone() else {
+            return Err(PushError::MalformedAPNSPayload(
+                "connect response without token".to_string(),
+            ));
+        };
+        submitter.set_token(&token).await;
+
+        debug!("Recieved connect response with token {:?}", token);
+
+        reader.set_keep_alive(tokio::spawn(
+            reader.clone().keep_alive_loop(submitter.clone()),
+        ));
+
+        submitter.set_state(1).await?;
+        submitter.filter().await?;
+        submitter.flush_pending().await?;
+
+        Ok(token)
+    }
+
+    pub async fn new(state: Option<APNSState>) -> Result<APNSConnection, PushError> {
+        APNSConnection::new_with_transport(Arc::new(CourierTransport::new()), state).await
+    }
+
+    // connect through a custom transport, such as an in-process courier for testing
+    pub async fn new_with_transport(
+        transport: Arc<dyn APNSTransport>,
+        state: Option<APNSState>,
+    ) -> Result<APNSConnection, PushError> {
+        let mut state = match state {
+            Some(state) => state,
+            None => {
+                let keypair = generate_push_cert().await?;
+                APNSState {
+                    keypair,
+                    token: None,
+                }
+            }
+        };
+        match state.needs_renewal(Duration::ZERO) {
+            Ok(false) => {}
+            Ok(true) => {
+                info!("push certificate expired, renewing");
+                state = APNSState {
+                    keypair: generate_push_cert().await?,
+                    token: None,
+                };
+            }
+            Err(err) => warn!("can't read push certificate expiry: {:?}", err),
+        }
+        let stream = transport.connect().await?;
+        let (read, writer) = split(stream);
+        let writer = APNSSubmitter::make(writer);
+        writer.add_topic("com.apple.madrid").await;
+        let shared = Arc::new(watch::channel(state.clone()).0);
+        let reader = APNSReader::new(transport, read, writer.clone(), shared.clone());
+
+        let token = match APNSConnection::init_conn(&writer, &reader, &mut state).await {
+            Ok(token) => token,
+            Err(err) => {
+                // nothing will own the reader, don't leave it reconnecting
+                reader.abort_tasks();
+                return Err(err);
+            }
+        };
+        shared.send_replace(state);
+        reader.set_conn_state(APNSConnectionState::Connected { token });
+
+        let conn: APNSConnection = APNSConnection {
+            reader,
+            submitter: writer.clone(),
+            state: shared,
+        };
+        Ok(conn)
+    }
+}
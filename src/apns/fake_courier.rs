@@ -0,0 +1,520 @@
+// An in-process stand-in for Apple's couriers, speaking the same framed protocol as
+// `APNSCommand`. Devices connect through `FakeCourier::transport`, and iMessages and
+// IDS queries on com.apple.madrid are routed between them so two simulated devices
+// can talk to each other without any network access.
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use openssl::{bn::BigNum, pkey::PKey, rsa::Rsa};
+use plist::{Dictionary, Value};
+use rand::Rng;
+use tokio::{
+    io::{duplex, split, AsyncWriteExt, DuplexStream},
+    sync::{
+        mpsc::{self, UnboundedSender},
+        Mutex, Notify,
+    },
+};
+
+use crate::{
+    bags::insert_bag,
+    config::get_config,
+    ids::{
+        identity::IDSIdentity,
+        user::{IDSUser, IDSUserType},
+    },
+    util::{gzip, plist_to_bin, ungzip, KeyPair},
+    PushError,
+};
+
+use super::{APNSCommand, APNSConnection, APNSPayload, APNSState, APNSStream, APNSTransport};
+
+const MADRID_TOPIC: &str = "com.apple.madrid";
+const FAKE_ID_QUERY: &str = "https://fake-courier.invalid/id-query";
+
+// a message a device sent through the courier
+#[derive(Debug, Clone)]
+pub struct SentMessage {
+    pub token: Vec<u8>,
+    pub topic: [u8; 20],
+    pub payload: Vec<u8>,
+    pub id: [u8; 4],
+}
+
+struct ConnectedDevice {
+    conn_id: u64,
+    sender: UnboundedSender<APNSCommand>,
+    topics: Vec<[u8; 20]>,
+    state: Option<u8>,
+    kill: Arc<Notify>,
+}
+
+// (topic hash, payload) waiting to be delivered
+type PendingNotification = ([u8; 20], Vec<u8>);
+
+#[derive(Default)]
+struct CourierState {
+    next_conn_id: u64,
+    devices: HashMap<Vec<u8>, ConnectedDevice>,
+    // notifications for devices that are offline or not filtering the topic yet
+    pending: HashMap<Vec<u8>, Vec<PendingNotification>>,
+    identities: HashMap<String, Vec<Value>>,
+    sent: Vec<SentMessage>,
+    // simulates a courier that went quiet without closing the socket
+    ignore_keep_alives: bool,
+    // answer connects that need a token without one
+    withhold_tokens: bool,
+    // new connections wait until released, like a slow network
+    hold_connects: bool,
+    released: Arc<Notify>,
+}
+
+#[derive(Clone, Default)]
+pub struct FakeCourier(Arc<Mutex<CourierState>>);
+
+impl FakeCourier {
+    pub fn new() -> FakeCourier {
+        FakeCourier::default()
+    }
+
+    // a transport that connects to this courier; use with `APNSConnection::new_with_transport`
+    pub fn transport(&self) -> Arc<dyn APNSTransport> {
+        Arc::new(FakeCourierTransport(self.clone()))
+    }
+
+    // delivers a notification to a device, as a provider would
+    pub async fn push(&self, token: &[u8], topic: &str, payload: &[u8]) {
+        let mut state = self.0.lock().await;
+        deliver(&mut state, token, APNSCommand::topic_hash(topic), payload);
+    }
+
+    // every 0x0A message devices have sent so far
+    pub async fn sent_messages(&self) -> Vec<SentMessage> {
+        self.0.lock().await.sent.clone()
+    }
+
+    // last 0x14 state a device reported
+    pub async fn device_state(&self, token: &[u8]) -> Option<u8> {
+        let state = self.0.lock().await;
+        state.devices.get(token).and_then(|device| device.state)
+    }
+
+    pub async fn is_connected(&self, token: &[u8]) -> bool {
+        self.0.lock().await.devices.contains_key(token)
+    }
+
+    // whether the device's last filter included `topic`
+    pub async fn is_filtering(&self, token: &[u8], topic: &str) -> bool {
+        let state = self.0.lock().await;
+        let hash = APNSCommand::topic_hash(topic);
+        state
+            .devices
+            .get(token)
+            .is_some_and(|device| device.topics.contains(&hash))
+    }
+
+    pub async fn set_ignore_keep_alives(&self, ignore: bool) {
+        self.0.lock().await.ignore_keep_alives = ignore;
+    }
+
+    pub async fn set_withhold_tokens(&self, withhold: bool) {
+        self.0.lock().await.withhold_tokens = withhold;
+    }
+
+    pub async fn set_hold_connects(&self, hold: bool) {
+        let mut state = self.0.lock().await;
+        state.hold_connects = hold;
+        if !hold {
+            state.released.notify_waiters();
+        }
+    }
+
+    async fn wait_for_release(&self) {
+        loop {
+            let released = self.0.lock().await.released.clone();
+            let notified = released.notified();
+            tokio::pin!(notified);
+            // registered before checking, so a release in between isn't missed
+            notified.as_mut().enable();
+            if !self.0.lock().await.hold_connects {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    // drops every open connection, as if the courier had gone away
+    pub async fn drop_connections(&self) {
+        let mut state = self.0.lock().await;
+        for (_, device) in state.devices.drain() {
+            device.kill.notify_one();
+        }
+    }
+
+    // answer IDS queries for `handle` with this identity
+    pub async fn publish_identity(
+        &self,
+        handle: &str,
+        public_identity: &[u8],
+        push_token: &[u8],
+        session_token: &[u8],
+    ) {
+        let identity = Value::Dictionary(Dictionary::from_iter([
+            (
+                "client-data",
+                Value::Dictionary(Dictionary::from_iter([(
+                    "public-message-identity-key",
+                    Value::Data(public_identity.to_vec()),
+                )])),
+            ),
+            ("push-token", Value::Data(push_token.to_vec())),
+            ("session-token", Value::Data(session_token.to_vec())),
+        ]));
+        let mut state = self.0.lock().await;
+        state
+            .identities
+            .entry(handle.to_string())
+            .or_default()
+            .push(identity);
+    }
+
+    // connects a simulated device owning `handle`, with an IDS identity published on this courier
+    pub async fn new_device(
+        &self,
+        handle: &str,
+    ) -> Result<(Arc<APNSConnection>, IDSUser), PushError> {
+        insert_bag(
+            &get_config().endpoints.ids_bag,
+            Dictionary::from_iter([("id-query", Value::String(FAKE_ID_QUERY.to_string()))]),
+        )
+        .await;
+
+        let push_key = PKey::from_rsa(Rsa::generate_with_e(
+            2048,
+            BigNum::from_u32(65537)?.as_ref(),
+        )?)?;
+        let conn = APNSConnection::new_with_transport(
+            self.transport(),
+            Some(APNSState {
+                keypair: KeyPair {
+                    cert: vec![],
+                    private: push_key.private_key_to_der()?,
+                },
+                token: None,
+            }),
+        )
+        .await?;
+
+        let auth_key = PKey::from_rsa(Rsa::generate_with_e(
+            2048,
+            BigNum::from_u32(65537)?.as_ref(),
+        )?)?;
+        let auth_keypair = KeyPair {
+            cert: vec![],
+            private: auth_key.private_key_to_der()?,
+        };
+        let mut identity = IDSIdentity::new()?;
+        identity.id_keypair = Some(auth_keypair.clone());
+
+        let token = conn.state().token.unwrap();
+        let session_token = rand::thread_rng().gen::<[u8; 16]>();
+        self.publish_identity(handle, &identity.encode(), &token, &session_token)
+            .await;
+
+        let user = IDSUser {
+            auth_keypair,
+            user_id: handle.to_string(),
+            handles: vec![handle.to_string()],
+            identity: Some(identity),
+            user_type: IDSUserType::Apple,
+            auth: None,
+        };
+        Ok((Arc::new(conn), user))
+    }
+
+    async fn serve(self, stream: DuplexStream) {
+        let stream: Box<dyn APNSStream> = Box::new(stream);
+        let (mut read, mut write) = split(stream);
+        let (sender, mut outbound) = mpsc::unbounded_channel::<APNSCommand>();
+        let writer = tokio::spawn(async move {
+            while let Some(command) = outbound.recv().await {
+                let data = match command.serialize() {
+                    Ok(data) => data,
+                    Err(err) => {
+                        warn!("fake courier: {:?}", err);
+                        continue;
+                    }
+                };
+                if write.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+            let _ = write.shutdown().await;
+        });
+
+        let kill = Arc::new(Notify::new());
+        let conn_id = {
+            let mut state = self.0.lock().await;
+            state.next_conn_id += 1;
+            state.next_conn_id
+        };
+        let mut token: Option<Vec<u8>> = None;
+        loop {
+            let payload = tokio::select! {
+                payload = APNSPayload::read(&mut read) => payload,
+                _ = kill.notified() => break,
+            };
+            let payload = match payload {
+                Ok(Some(payload)) => payload,
+                Ok(None) => continue,
+                Err(_) => break,
+            };
+            let command = match APNSCommand::decode_outgoing(&payload) {
+                Ok(command) => command,
+                Err(err) => {
+                    warn!("fake courier: {:?}", err);
+                    continue;
+                }
+            };
+            let mut state = self.0.lock().await;
+            match command {
+                APNSCommand::Connect {
+                    token: existing, ..
+                } => {
+                    let is_new = existing.is_none() && !state.withhold_tokens;
+                    let assigned =
+                        existing.unwrap_or_else(|| rand::thread_rng().gen::<[u8; 32]>().to_vec());
+                    let _ = sender.send(APNSCommand::ConnectResponse {
+                        status: 0x00,
+                        token: is_new.then(|| assigned.clone()),
+                    });
+                    if let Some(old) = state.devices.insert(
+                        assigned.clone(),
+                        ConnectedDevice {
+                            conn_id,
+                            sender: sender.clone(),
+                            topics: vec![],
+                            state: None,
+                            kill: kill.clone(),
+                        },
+                    ) {
+                        old.kill.notify_one();
+                    }
+                    debug!("fake courier: device connected {:?}", assigned);
+                    token = Some(assigned);
+                }
+                APNSCommand::Filter { topics, .. } => {
+                    let Some(token) = &token else { continue };
+                    if let Some(device) = state.devices.get_mut(token) {
+                        device.topics = topics;
+                    }
+                    flush_pending(&mut state, token);
+                }
+                APNSCommand::SendMessage {
+                    id, topic, payload, ..
+                } => {
+                    let Some(token) = token.clone() else { continue };
+                    let _ = sender.send(APNSCommand::Ack {
+                        token: None,
+                        id,
+                        status: 0x00,
+                    });
+                    if topic == APNSCommand::topic_hash(MADRID_TOPIC) {
+                        route_madrid(&mut state, &token, &payload);
+                    }
+                    state.sent.push(SentMessage {
+                        token,
+                        topic,
+                        payload,
+                        id,
+                    });
+                }
+                APNSCommand::Ack { id, .. } => {
+                    debug!("fake courier: ack for {:?}", id);
+                }
+                APNSCommand::KeepAlive => {
+                    if !state.ignore_keep_alives {
+                        let _ = sender.send(APNSCommand::KeepAliveAck);
+                    }
+                }
+                APNSCommand::SetState { state: new_state } => {
+                    let Some(token) = &token else { continue };
+                    if let Some(device) = state.devices.get_mut(token) {
+                        device.state = Some(new_state);
+                    }
+                }
+                command => {
+                    debug!("fake courier: ignoring command {:#x}", command.id());
+                }
+            }
+        }
+
+        if let Some(token) = token {
+            let mut state = self.0.lock().await;
+            if state
+                .devices
+                .get(&token)
+                .is_some_and(|device| device.conn_id == conn_id)
+            {
+                state.devices.remove(&token);
+            }
+        }
+        writer.abort();
+    }
+}
+
+struct FakeCourierTransport(FakeCourier);
+
+#[async_trait]
+impl APNSTransport for FakeCourierTransport {
+    async fn connect(&self) -> Result<Box<dyn APNSStream>, PushError> {
+        self.0.wait_for_release().await;
+        let (client, server) = duplex(1 << 16);
+        tokio::spawn(self.0.clone().serve(server));
+        Ok(Box::new(client))
+    }
+}
+
+fn deliver(state: &mut CourierState, token: &[u8], topic: [u8; 20], payload: &[u8]) {
+    if let Some(device) = state.devices.get(token) {
+        if device.topics.contains(&topic) {
+            let sent = device.sender.send(APNSCommand::Notification {
+                id: rand::thread_rng().gen(),
+                topic,
+                token: token.to_vec(),
+                payload: payload.to_vec(),
+            });
+            if sent.is_ok() {
+                return;
+            }
+        }
+    }
+    state
+        .pending
+        .entry(token.to_vec())
+        .or_default()
+        .push((topic, payload.to_vec()));
+}
+
+fn flush_pending(state: &mut CourierState, token: &[u8]) {
+    let Some(pending) = state.pending.remove(token) else {
+        return;
+    };
+    for (topic, payload) in pending {
+        deliver(state, token, topic, &payload);
+    }
+}
+
+fn route_madrid(state: &mut CourierState, sender_token: &[u8], body: &[u8]) {
+    let Ok(loaded) = plist::Value::from_reader(Cursor::new(body)) else {
+        warn!("fake courier: madrid payload is not a plist");
+        return;
+    };
+    let Some(dict) = loaded.as_dictionary() else {
+        return;
+    };
+    match dict.get("c").and_then(|c| c.as_unsigned_integer()) {
+        Some(96) => answer_query(state, sender_token, dict),
+        Some(_) => fan_out(state, sender_token, dict),
+        None => warn!("fake courier: madrid payload without command"),
+    }
+}
+
+fn answer_query(state: &mut CourierState, sender_token: &[u8], request: &Dictionary) {
+    let (Some(msg_id), Some(body)) = (
+        request.get("U").and_then(|u| u.as_data()),
+        request.get("b").and_then(|b| b.as_data()),
+    ) else {
+        warn!("fake courier: malformed IDS query");
+        return;
+    };
+    let Ok(query) = ungzip(body)
+        .map_err(PushError::from)
+        .and_then(|body| Ok(plist::Value::from_reader(Cursor::new(body))?))
+    else {
+        warn!("fake courier: undecodable IDS query");
+        return;
+    };
+    let uris = query
+        .as_dictionary()
+        .and_then(|query| query.get("uris"))
+        .and_then(|uris| uris.as_array())
+        .cloned()
+        .unwrap_or_default();
+
+    let results = Dictionary::from_iter(uris.iter().filter_map(|uri| {
+        let uri = uri.as_string()?;
+        let identities = state.identities.get(uri).cloned().unwrap_or_default();
+        Some((
+            uri.to_string(),
+            Value::Dictionary(Dictionary::from_iter([(
+                "identities",
+                Value::Array(identities),
+            )])),
+        ))
+    }));
+    let lookup = Value::Dictionary(Dictionary::from_iter([
+        ("status", Value::Integer(0.into())),
+        ("results", Value::Dictionary(results)),
+    ]));
+    let Ok(encoded) = plist_to_bin(&lookup)
+        .map_err(PushError::from)
+        .and_then(|bin| Ok(gzip(&bin)?))
+    else {
+        return;
+    };
+    let response = Value::Dictionary(Dictionary::from_iter([
+        ("c", Value::Integer(97.into())),
+        ("U", Value::Data(msg_id.to_vec())),
+        ("s", Value::Integer(0.into())),
+        ("b", Value::Data(encoded)),
+    ]));
+    if let Ok(response) = plist_to_bin(&response) {
+        deliver(
+            state,
+            sender_token,
+            APNSCommand::topic_hash(MADRID_TOPIC),
+            &response,
+        );
+    }
+}
+
+fn fan_out(state: &mut CourierState, sender_token: &[u8], message: &Dictionary) {
+    let Some(targets) = message.get("dtl").and_then(|dtl| dtl.as_array()) else {
+        return;
+    };
+    let sent_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_nanos() as u64;
+    for target in targets {
+        let Some(target) = target.as_dictionary() else {
+            continue;
+        };
+        let Some(token) = target.get("t").and_then(|t| t.as_data()) else {
+            continue;
+        };
+        let mut recv = Dictionary::new();
+        for key in ["c", "U", "sP", "eX", "E", "nr"] {
+            if let Some(value) = message.get(key) {
+                recv.insert(key.to_string(), value.clone());
+            }
+        }
+        for key in ["tP", "P"] {
+            if let Some(value) = target.get(key) {
+                recv.insert(key.to_string(), value.clone());
+            }
+        }
+        recv.insert("t".to_string(), Value::Data(sender_token.to_vec()));
+        recv.insert("e".to_string(), Value::Integer(sent_at.into()));
+        if let Ok(body) = plist_to_bin(&Value::Dictionary(recv)) {
+            deliver(state, token, APNSCommand::topic_hash(MADRID_TOPIC), &body);
+        }
+    }
+}
@@ -0,0 +1,13 @@
+pub mod command;
+pub mod connection;
+#[cfg(feature = "test-support")]
+pub mod fake_courier;
+pub mod queue;
+pub mod transport;
+
+pub use command::{APNSCommand, APNSPayload};
+pub use connection::{
+    APNSConnection, APNSConnectionState, APNSState, APNSSubscription, KeepAlivePolicy,
+};
+pub use queue::{APNSReceiver, OverflowPolicy, SubscriberOptions};
+pub use transport::{APNSStream, APNSTransport, CourierTransport};
@@ -0,0 +1,193 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use log::warn;
+use tokio::sync::Notify;
+
+use crate::PushError;
+
+use super::command::APNSCommand;
+
+// what a subscriber's buffer does when it is full and another command arrives
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    // stop reading from the courier until there is room. nothing is lost, but a subscriber
+    // that stops reading stalls the whole connection: acks, keep-alive replies and every
+    // other subscriber wait with it, and missed keep-alives end in a reconnect
+    Block,
+    // discard the oldest buffered command to make room
+    DropOldest,
+    // discard the new command; the next `recv` reports how many were lost
+    Error,
+    // stop delivering to this subscriber, it ends once the buffer is drained
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SubscriberOptions {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for SubscriberOptions {
+    fn default() -> Self {
+        SubscriberOptions {
+            capacity: 20,
+            overflow: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+struct QueueState {
+    items: VecDeque<APNSCommand>,
+    // lost to overflow and not reported yet
+    dropped: usize,
+    sender_closed: bool,
+    receiver_closed: bool,
+    disconnected: bool,
+}
+
+struct Queue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    // wakes a `Block` sender once the subscriber takes something
+    space: Notify,
+    options: SubscriberOptions,
+}
+
+pub(crate) fn queue(options: SubscriberOptions) -> (QueueSender, APNSReceiver) {
+    let queue = Arc::new(Queue {
+        state: Mutex::new(QueueState {
+            items: VecDeque::new(),
+            dropped: 0,
+            sender_closed: false,
+            receiver_closed: false,
+            disconnected: false,
+        }),
+        notify: Notify::new(),
+        space: Notify::new(),
+        options,
+    });
+    (QueueSender(queue.clone()), APNSReceiver(queue))
+}
+
+// the reader's end; only waits under `OverflowPolicy::Block`
+pub(crate) struct QueueSender(Arc<Queue>);
+
+impl QueueSender {
+    pub(crate) fn is_closed(&self) -> bool {
+        let state = self.0.state.lock().unwrap();
+        state.receiver_closed || state.disconnected
+    }
+
+    // returns false once the subscriber should be dropped
+    pub(crate) async fn push(&self, command: APNSCommand) -> bool {
+        if self.0.options.overflow == OverflowPolicy::Block {
+            self.wait_for_space().await;
+        }
+        let mut state = self.0.state.lock().unwrap();
+        if state.receiver_closed || state.disconnected {
+            return false;
+        }
+        if state.items.len() >= self.0.options.capacity {
+            match self.0.options.overflow {
+                // the subscriber went away while we waited
+                OverflowPolicy::Block => return false,
+                OverflowPolicy::DropOldest => {
+                    warn!("subscriber is full, dropping oldest command");
+                    state.items.pop_front();
+                    state.dropped += 1;
+                }
+                OverflowPolicy::Error => {
+                    warn!("subscriber is full, dropping command");
+                    state.dropped += 1;
+                    drop(state);
+                    self.0.notify.notify_one();
+                    return true;
+                }
+                OverflowPolicy::Disconnect => {
+                    warn!("subscriber is full, disconnecting it");
+                    state.dropped += 1;
+                    state.disconnected = true;
+                    drop(state);
+                    self.0.notify.notify_one();
+                    return false;
+                }
+            }
+        }
+        state.items.push_back(command);
+        drop(state);
+        self.0.notify.notify_one();
+        true
+    }
+}
+
+impl QueueSender {
+    async fn wait_for_space(&self) {
+        loop {
+            {
+                let state = self.0.state.lock().unwrap();
+                if state.receiver_closed || state.items.len() < self.0.options.capacity {
+                    return;
+                }
+            }
+            self.0.space.notified().await;
+        }
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().sender_closed = true;
+        self.0.notify.notify_one();
+    }
+}
+
+pub struct APNSReceiver(Arc<Queue>);
+
+impl APNSReceiver {
+    // Err(APNSConnectionClosed) once the connection is shut down and the buffer is empty
+    pub async fn recv(&mut self) -> Result<APNSCommand, PushError> {
+        loop {
+            if let Some(result) = self.try_recv().transpose() {
+                return result;
+            }
+            self.0.notify.notified().await;
+        }
+    }
+
+    // Ok(None) if nothing is buffered right now
+    pub fn try_recv(&mut self) -> Result<Option<APNSCommand>, PushError> {
+        let mut state = self.0.state.lock().unwrap();
+        let overflow = self.0.options.overflow;
+        if overflow == OverflowPolicy::Error && state.dropped > 0 {
+            let dropped = std::mem::take(&mut state.dropped);
+            return Err(PushError::APNSSubscriberOverflow(dropped));
+        }
+        if let Some(item) = state.items.pop_front() {
+            self.0.space.notify_one();
+            return Ok(Some(item));
+        }
+        if state.disconnected {
+            return Err(PushError::APNSSubscriberOverflow(state.dropped));
+        }
+        if state.sender_closed {
+            return Err(PushError::APNSConnectionClosed);
+        }
+        Ok(None)
+    }
+
+    // commands discarded so far under `DropOldest`
+    pub fn dropped(&self) -> usize {
+        self.0.state.lock().unwrap().dropped
+    }
+}
+
+impl Drop for APNSReceiver {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().receiver_closed = true;
+        self.0.space.notify_one();
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use openssl::x509::X509;
+use rand::seq::SliceRandom;
+use rustls::Certificate;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{lookup_host, TcpStream},
+    time::timeout,
+};
+use tokio_rustls::TlsConnector;
+
+use crate::{bags::get_apns_bag, config::get_config, PushError};
+
+// anything APNs frames can be read from and written to
+pub trait APNSStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> APNSStream for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+// opens streams to a courier, called for the first connection and for every reconnect
+#[async_trait]
+pub trait APNSTransport: Send + Sync {
+    async fn connect(&self) -> Result<Box<dyn APNSStream>, PushError>;
+}
+
+const APNS_PORT: u16 = 5223;
+
+// how long a host that failed to connect is tried last
+const FAILURE_COOLDOWN: Duration = Duration::from_secs(300);
+
+// connects to Apple's couriers over TLS
+// every courier host from the bag is tried in random order, hosts that failed recently go last,
+// and every address a host resolves to is tried before moving on.
+pub struct CourierTransport {
+    // dial this host and port instead of the bag's couriers
+    host_override: Option<(String, u16)>,
+    recently_failed: Mutex<HashMap<String, Instant>>,
+}
+
+impl Default for CourierTransport {
+    fn default() -> Self {
+        CourierTransport::new()
+    }
+}
+
+impl CourierTransport {
+    pub fn new() -> CourierTransport {
+        CourierTransport {
+            host_override: None,
+            recently_failed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // for testing environments; the host is also used as the TLS server name
+    pub fn with_host(host: &str, port: u16) -> CourierTransport {
+        CourierTransport {
+            host_override: Some((host.to_string(), port)),
+            recently_failed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // (host to dial, port, name to verify the certificate against)
+    async fn candidates(&self) -> Result<Vec<(String, u16, String)>, PushError> {
+        if let Some((host, port)) = &self.host_override {
+            return Ok(vec![(host.clone(), *port, host.clone())]);
+        }
+        let bag = get_apns_bag().await?;
+        let hostname = bag.apns_courier_hostname()?.to_string();
+        let count = bag.apns_courier_hostcount()?;
+
+        let mut hosts: Vec<String> = (1..=count).map(|i| format!("{}-{}", i, hostname)).collect();
+        hosts.shuffle(&mut rand::thread_rng());
+
+        let mut failed = self.recently_failed.lock().unwrap();
+        failed.retain(|_, when| when.elapsed() < FAILURE_COOLDOWN);
+        // stable sort keeps the shuffle within each group, oldest failures first
+        hosts.sort_by_key(|host| failed.get(host).copied());
+
+        Ok(hosts
+            .into_iter()
+            .map(|host| (host, APNS_PORT, hostname.clone()))
+            .collect())
+    }
+
+    async fn connect_host(
+        &self,
+        connector: &TlsConnector,
+        host: &str,
+        port: u16,
+        server_name: &str,
+    ) -> Result<Box<dyn APNSStream>, PushError> {
+        let domain = rustls::ServerName::try_from(server_name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid dnsname"))?;
+
+        // the proxy resolves the host itself
+        let config = get_config();
+        if let Some(proxy) = &config.proxy {
+            let stream = timeout(config.connect_timeout, proxy.connect(host, port))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+            let connection = connector.connect(domain, stream).await?;
+            info!("Connected to APNs ({} via proxy)", host);
+            return Ok(Box::new(connection));
+        }
+
+        let mut last_err: PushError = io::Error::from(io::ErrorKind::NotFound).into();
+        for addr in lookup_host((host, port)).await? {
+            let stream = match timeout(config.connect_timeout, TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    warn!("failed to connect to {} ({}): {:?}", host, addr, err);
+                    last_err = err.into();
+                    continue;
+                }
+                Err(_) => {
+                    warn!("timed out connecting to {} ({})", host, addr);
+                    last_err = io::Error::from(io::ErrorKind::TimedOut).into();
+                    continue;
+                }
+            };
+            match connector.connect(domain.clone(), stream).await {
+                Ok(connection) => {
+                    info!("Connected to APNs ({}, {})", host, addr);
+                    return Ok(Box::new(connection));
+                }
+                Err(err) => {
+                    warn!("TLS handshake with {} ({}) failed: {:?}", host, addr, err);
+                    last_err = err.into();
+                }
+            }
+        }
+        Err(last_err)
+    }
+}
+
+#[async_trait]
+impl APNSTransport for CourierTransport {
+    async fn connect(&self) -> Result<Box<dyn APNSStream>, PushError> {
+        let x509 = X509::from_pem(include_bytes!(
+            "../../certs/root/profileidentity.ess.apple.com.cert"
+        ))?;
+        let certificate = Certificate(x509.to_der()?);
+
+        let mut root_store = rustls::RootCertStore::empty();
+        root_store.add(&certificate)?;
+
+        let mut config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+        config.alpn_protocols.push(b"apns-security-v3".to_vec());
+
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let mut last_err = PushError::APNSConnectError;
+        for (host, port, server_name) in self.candidates().await? {
+            match self
+                .connect_host(&connector, &host, port, &server_name)
+                .await
+            {
+                Ok(stream) => {
+                    self.recently_failed.lock().unwrap().remove(&host);
+                    return Ok(stream);
+                }
+                Err(err) => {
+                    self.recently_failed
+                        .lock()
+                        .unwrap()
+                        .insert(host, Instant::now());
+                    last_err = err;
+                }
+            }
+        }
+        Err(last_err)
+    }
+}
@@ -1,41 +1,312 @@
 use lazy_static::lazy_static;
+use log::{debug, warn};
+use openssl::{
+    hash::MessageDigest,
+    nid::Nid,
+    sign::Verifier,
+    stack::Stack,
+    x509::{store::X509StoreBuilder, verify::X509VerifyFlags, X509NameRef, X509StoreContext, X509},
+};
 use plist::{Data, Dictionary};
-use serde::Deserialize;
-use std::{collections::HashMap, io::Cursor};
-use tokio::sync::Mutex;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, sync::Mutex};
 
-use crate::{util::make_reqwest, PushError};
+use crate::{
+    config::{api_request, get_config, RustpushConfig},
+    PushError,
+};
 
-#[derive(Deserialize)]
+// the signed response, kept whole so cached bags can be checked again
+#[derive(Serialize, Deserialize, Clone)]
 struct BagResult {
     bag: Data,
+    // DER, signing certificate first
+    certs: Option<Vec<Data>>,
+    signature: Option<Data>,
 }
 
-pub const APNS_BAG: &str = "http://init-p01st.push.apple.com/bag";
-pub const IDS_BAG: &str = "https://init.ess.apple.com/WebObjects/VCInit.woa/wa/getBag?ix=3";
+fn name_entry(name: &X509NameRef, nid: Nid) -> Option<String> {
+    let entry = name.entries_by_nid(nid).next()?;
+    Some(entry.data().as_utf8().ok()?.to_string())
+}
 
-lazy_static! {
-    static ref BAG_CACHE: Mutex<HashMap<String, Dictionary>> = Mutex::new(HashMap::new());
+// the signer must be one of Apple's own servers, not just anything the CA has issued
+fn check_signer(leaf: &X509) -> Result<(), PushError> {
+    let subject = leaf.subject_name();
+    let organization = name_entry(subject, Nid::ORGANIZATIONNAME);
+    let common_name = name_entry(subject, Nid::COMMONNAME).unwrap_or_default();
+    if organization.as_deref() != Some("Apple Inc.") || !common_name.ends_with(".apple.com") {
+        return Err(PushError::BagSignatureError(format!(
+            "bag signed by {:?} ({:?}), not an Apple server",
+            common_name, organization
+        )));
+    }
+    Ok(())
+}
+
+impl BagResult {
+    // the signature must come from an Apple server cert, which must chain to one of `roots`
+    fn verify(&self, roots: &[Vec<u8>]) -> Result<(), PushError> {
+        let (Some(certs), Some(signature)) = (&self.certs, &self.signature) else {
+            return Err(PushError::BagSignatureError(
+                "bag is not signed".to_string(),
+            ));
+        };
+        let mut certs = certs.iter().map(|cert| X509::from_der(cert.as_ref()));
+        let Some(leaf) = certs.next() else {
+            return Err(PushError::BagSignatureError("no certificates".to_string()));
+        };
+        let leaf = leaf?;
+        check_signer(&leaf)?;
+        let mut chain = Stack::new()?;
+        for cert in certs {
+            chain.push(cert?)?;
+        }
+
+        let mut store = X509StoreBuilder::new()?;
+        for root in roots {
+            store.add_cert(X509::from_pem(root)?)?;
+        }
+        // the pinned roots are Apple's intermediates
+        store.set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+        let store = store.build();
+
+        let mut context = X509StoreContext::new()?;
+        let chain_error = context.init(&store, &leaf, &chain, |context| {
+            Ok(match context.verify_cert()? {
+                true => None,
+                false => Some(context.error().to_string()),
+            })
+        })?;
+        if let Some(err) = chain_error {
+            return Err(PushError::BagSignatureError(err));
+        }
+
+        let key = leaf.public_key()?;
+        // accept either digest
+        for digest in [MessageDigest::sha1(), MessageDigest::sha256()] {
+            let mut verifier = Verifier::new(digest, &key)?;
+            verifier.update(self.bag.as_ref())?;
+            if verifier.verify(signature.as_ref())? {
+                return Ok(());
+            }
+        }
+        Err(PushError::BagSignatureError(
+            "signature does not match".to_string(),
+        ))
+    }
+
+    fn dictionary(&self) -> Result<Dictionary, PushError> {
+        plist::Value::from_reader(Cursor::new(&self.bag))?
+            .into_dictionary()
+            .ok_or_else(|| PushError::BagKeyNotFound("bag".to_string()))
+    }
+}
+
+// parses a bag response, checking its signature against `roots` (PEM)
+pub fn verify_bag(response: &[u8], roots: &[Vec<u8>]) -> Result<Dictionary, PushError> {
+    let parsed: BagResult = plist::from_bytes(response)?;
+    parsed.verify(roots)?;
+    parsed.dictionary()
+}
+
+// a fetched bag, with typed accessors for the keys we use
+#[derive(Debug, Clone)]
+pub struct Bag(Dictionary);
+
+impl Bag {
+    pub fn get_str(&self, key: &str) -> Result<&str, PushError> {
+        self.0
+            .get(key)
+            .and_then(|v| v.as_string())
+            .ok_or_else(|| PushError::BagKeyNotFound(key.to_string()))
+    }
+
+    pub fn get_unsigned(&self, key: &str) -> Result<u64, PushError> {
+        self.0
+            .get(key)
+            .and_then(|v| v.as_unsigned_integer())
+            .ok_or_else(|| PushError::BagKeyNotFound(key.to_string()))
+    }
+
+    pub fn apns_courier_hostname(&self) -> Result<&str, PushError> {
+        self.get_str("APNSCourierHostname")
+    }
+
+    pub fn apns_courier_hostcount(&self) -> Result<u64, PushError> {
+        self.get_unsigned("APNSCourierHostcount")
+    }
+
+    pub fn id_query(&self) -> Result<&str, PushError> {
+        self.get_str("id-query")
+    }
+
+    pub fn id_get_handles(&self) -> Result<&str, PushError> {
+        self.get_str("id-get-handles")
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedBag {
+    response: BagResult,
+    // unix seconds
+    fetched: u64,
+}
+
+impl CachedBag {
+    fn new(response: BagResult) -> CachedBag {
+        CachedBag {
+            response,
+            fetched: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+
+    fn is_fresh(&self, ttl: Duration) -> bool {
+        let fetched = UNIX_EPOCH + Duration::from_secs(self.fetched);
+        SystemTime::now()
+            .duration_since(fetched)
+            .map_or(true, |age| age < ttl)
+    }
+}
+
+#[derive(Default)]
+struct BagCache {
+    bags: HashMap<String, CachedBag>,
+    // which file `bags` was loaded from, if any
+    loaded_from: Option<PathBuf>,
 }
-pub async fn get_bag(bag_url: &str) -> Result<Dictionary, PushError> {
-    let mut cache = BAG_CACHE.lock().await;
 
-    if let Some(bag) = cache.get(bag_url) {
-        return Ok(bag.clone());
+impl BagCache {
+    // with `verify`, entries whose signature doesn't check out are skipped
+    async fn load(&mut self, path: &PathBuf, verify: Option<&[Vec<u8>]>) {
+        if self.loaded_from.as_ref() == Some(path) {
+            return;
+        }
+        self.loaded_from = Some(path.clone());
+        let Ok(data) = fs::read(path).await else {
+            return;
+        };
+        match plist::from_bytes::<HashMap<String, CachedBag>>(&data) {
+            Ok(saved) => {
+                for (url, bag) in saved {
+                    if let Some(roots) = verify {
+                        if let Err(err) = bag.response.verify(roots) {
+                            warn!("ignoring cached bag {}: {:?}", url, err);
+                            continue;
+                        }
+                    }
+                    self.bags.entry(url).or_insert(bag);
+                }
+            }
+            Err(err) => warn!("ignoring unreadable bag cache {:?}: {:?}", path, err),
+        }
     }
 
-    let client = make_reqwest();
-    let content = client.get(bag_url).send().await?;
+    async fn save(&self, path: &PathBuf) {
+        let mut data = vec![];
+        if let Err(err) = plist::to_writer_xml(&mut data, &self.bags) {
+            warn!("failed to serialize bag cache: {:?}", err);
+            return;
+        }
+        if let Err(err) = fs::write(path, data).await {
+            warn!("failed to save bag cache to {:?}: {:?}", path, err);
+        }
+    }
+}
+
+lazy_static! {
+    static ref BAG_CACHE: Mutex<BagCache> = Mutex::new(BagCache::default());
+}
+
+async fn fetch_bag(bag_url: &str, config: &RustpushConfig) -> Result<BagResult, PushError> {
+    let content = api_request(Method::GET, bag_url).send().await?;
     if !content.status().is_success() {
         return Err(PushError::StatusError(content.status()));
     }
 
     let data = content.bytes().await?;
     let parsed: BagResult = plist::from_bytes(&data)?;
-    let bag = plist::Value::from_reader(Cursor::new(&parsed.bag))?;
+    if config.verify_bags {
+        parsed.verify(&config.bag_signing_roots)?;
+    }
+    // fail now rather than caching something unusable
+    parsed.dictionary()?;
+    Ok(parsed)
+}
+
+// cached for `RustpushConfig::bag_ttl`; if a refresh fails, the last bag we had is used instead,
+// unless the new bag's signature was bad
+pub async fn get_bag(bag_url: &str) -> Result<Bag, PushError> {
+    let config = get_config();
+    let mut cache = BAG_CACHE.lock().await;
+    if let Some(path) = &config.bag_cache_path {
+        let roots = config
+            .verify_bags
+            .then_some(config.bag_signing_roots.as_slice());
+        cache.load(path, roots).await;
+    }
+
+    if let Some(cached) = cache.bags.get(bag_url) {
+        if cached.is_fresh(config.bag_ttl) {
+            return Ok(Bag(cached.response.dictionary()?));
+        }
+        debug!("bag {} expired, refreshing", bag_url);
+    }
+
+    let response = match fetch_bag(bag_url, &config).await {
+        Ok(response) => response,
+        Err(err @ PushError::BagSignatureError(_)) => return Err(err),
+        Err(err) => {
+            let Some(cached) = cache.bags.get(bag_url) else {
+                return Err(err);
+            };
+            warn!("failed to refresh bag {}, using cached: {:?}", bag_url, err);
+            return Ok(Bag(cached.response.dictionary()?));
+        }
+    };
 
-    let bag = bag.as_dictionary().unwrap().clone();
-    cache.insert(bag_url.to_string(), bag.clone());
+    let bag = response.dictionary()?;
+    cache
+        .bags
+        .insert(bag_url.to_string(), CachedBag::new(response));
+    if let Some(path) = &config.bag_cache_path {
+        cache.save(path).await;
+    }
+
+    Ok(Bag(bag))
+}
+
+pub async fn get_apns_bag() -> Result<Bag, PushError> {
+    get_bag(&get_config().endpoints.apns_bag).await
+}
+
+pub async fn get_ids_bag() -> Result<Bag, PushError> {
+    get_bag(&get_config().endpoints.ids_bag).await
+}
 
-    Ok(bag)
+// pre-populate the cache, so lookups never hit the network
+#[cfg(feature = "test-support")]
+pub(crate) async fn insert_bag(bag_url: &str, bag: Dictionary) {
+    let mut data = vec![];
+    plist::to_writer_binary(&mut data, &bag).unwrap();
+    let response = BagResult {
+        bag: data.into(),
+        certs: None,
+        signature: None,
+    };
+    BAG_CACHE
+        .lock()
+        .await
+        .bags
+        .insert(bag_url.to_string(), CachedBag::new(response));
 }
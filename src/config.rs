@@ -0,0 +1,264 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use reqwest::{Certificate, Client, IntoUrl, Method, RequestBuilder};
+
+use crate::{proxy::ProxyConfig, PushError};
+
+// where we talk to; point these at local stand-ins for testing
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoints {
+    pub apns_bag: String,
+    pub ids_bag: String,
+    pub albert_activation: String,
+    pub ids_authenticate: String,
+    pub ids_register: String,
+    pub gsa: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Endpoints {
+            apns_bag: "http://init-p01st.push.apple.com/bag".to_string(),
+            ids_bag: "https://init.ess.apple.com/WebObjects/VCInit.woa/wa/getBag?ix=3".to_string(),
+            albert_activation:
+                "https://albert.apple.com/WebObjects/ALUnbrick.woa/wa/deviceActivation"
+                    .to_string(),
+            ids_authenticate:
+                "https://profile.ess.apple.com/WebObjects/VCProfileService.woa/wa/authenticateUser"
+                    .to_string(),
+            ids_register:
+                "https://identity.ess.apple.com/WebObjects/TDIdentityService.woa/wa/register"
+                    .to_string(),
+            gsa: "https://gsa.apple.com".to_string(),
+        }
+    }
+}
+
+// what we tell Albert when activating; `device_class` is also sent as the `device=` query on
+// `Endpoints::albert_activation`, so the two can't disagree
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActivationDevice {
+    pub device_class: String,
+    pub product_type: String,
+    pub product_version: String,
+    pub build_version: String,
+    pub serial_number: String,
+    // random for every activation if unset
+    pub unique_device_id: Option<String>,
+}
+
+impl Default for ActivationDevice {
+    fn default() -> Self {
+        ActivationDevice {
+            device_class: "Windows".to_string(),
+            product_type: "windows1,1".to_string(),
+            product_version: "10.6.4".to_string(),
+            build_version: "10.6.4".to_string(),
+            serial_number: "WindowSerial".to_string(),
+            unique_device_id: None,
+        }
+    }
+}
+
+// the Apple client we claim to be once activated
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientProfile {
+    // platform name in `ua` strings and the IDS os-version
+    pub os_name: String,
+    // hardware model, e.g. MacBookPro18,3
+    pub product_type: String,
+    pub product_version: String,
+    pub build_version: String,
+}
+
+impl ClientProfile {
+    pub fn mac(product_type: &str, product_version: &str, build_version: &str) -> ClientProfile {
+        ClientProfile {
+            os_name: "macOS".to_string(),
+            product_type: product_type.to_string(),
+            product_version: product_version.to_string(),
+            build_version: build_version.to_string(),
+        }
+    }
+
+    // e.g. [macOS,13.4.1,22F82,MacBookPro18,3]
+    pub(crate) fn ua(&self) -> String {
+        format!(
+            "[{},{},{},{}]",
+            self.os_name, self.product_version, self.build_version, self.product_type
+        )
+    }
+
+    // e.g. macOS,13.4.1,22F82
+    pub(crate) fn os_version(&self) -> String {
+        format!(
+            "{},{},{}",
+            self.os_name, self.product_version, self.build_version
+        )
+    }
+
+    pub(crate) fn mme_client_info(&self) -> String {
+        format!(
+            "<{}> <{};{};{}> <com.apple.icloud.content/1950.19 (com.apple.Messenger/1.0)>",
+            self.product_type, self.os_name, self.product_version, self.build_version
+        )
+    }
+}
+
+// the devices we claim to be; the defaults are what rustpush has always sent, which is not
+// one consistent device, so each service gets its own
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceProfile {
+    pub activation: ActivationDevice,
+    // IDS registration and GSA
+    pub ids: ClientProfile,
+    // the `ua` of outgoing iMessages
+    pub imessage: ClientProfile,
+    // MMCS requests and the `ua` of attachment uploads and downloads
+    pub mmcs: ClientProfile,
+}
+
+impl Default for DeviceProfile {
+    fn default() -> Self {
+        DeviceProfile {
+            activation: ActivationDevice::default(),
+            ids: ClientProfile::mac("MacBookPro18,3", "13.2.1", "22D68"),
+            imessage: ClientProfile::mac("MacBookPro18,3", "13.4.1", "22F82"),
+            mmcs: ClientProfile::mac("iMac13,1", "12.6.9", "21G726"),
+        }
+    }
+}
+
+impl DeviceProfile {
+    // one client identity for IDS, iMessage and MMCS
+    pub fn new(activation: ActivationDevice, client: ClientProfile) -> DeviceProfile {
+        DeviceProfile {
+            activation,
+            ids: client.clone(),
+            imessage: client.clone(),
+            mmcs: client,
+        }
+    }
+}
+
+// settings shared by every subsystem, see `set_config`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RustpushConfig {
+    pub endpoints: Endpoints,
+    pub device: DeviceProfile,
+    // PEM roots HTTPS servers must chain to, the system store is never used
+    pub pinned_roots: Vec<Vec<u8>>,
+    // whole-request limit for IDS, Albert, GSA and bag calls; MMCS transfers have none
+    pub request_timeout: Duration,
+    // for HTTPS and the courier socket
+    pub connect_timeout: Duration,
+    pub mmcs_user_agent: String,
+    pub proxy: Option<ProxyConfig>,
+    // how long fetched bags are used before refreshing them
+    pub bag_ttl: Duration,
+    // keeps bags across restarts, so we can reconnect without fetching them
+    pub bag_cache_path: Option<PathBuf>,
+    // PEM CAs bags must be signed under; separate from `pinned_roots`, which also trusts
+    // public web CAs
+    pub bag_signing_roots: Vec<Vec<u8>>,
+    // check bag signatures against `bag_signing_roots`; the APNs bag comes over plain http, so
+    // only turn off for local stand-ins
+    pub verify_bags: bool,
+}
+
+impl Default for RustpushConfig {
+    fn default() -> Self {
+        RustpushConfig {
+            endpoints: Endpoints::default(),
+            device: DeviceProfile::default(),
+            pinned_roots: vec![
+                include_bytes!("../certs/root/albert.apple.com.digicert.cert").to_vec(),
+                // Apple Server Authentication CA; gsa.apple.com chains to it too
+                include_bytes!("../certs/root/profileidentity.ess.apple.com.cert").to_vec(),
+                include_bytes!("../certs/root/init-p01st.push.apple.com.cert").to_vec(),
+                include_bytes!("../certs/root/init.ess.apple.com.cert").to_vec(),
+                include_bytes!("../certs/root/content-icloud-com.cert").to_vec(),
+            ],
+            request_timeout: Duration::from_secs(60),
+            connect_timeout: Duration::from_secs(15),
+            mmcs_user_agent: "IMTransferAgent/1000 CFNetwork/1335.0.3.4 Darwin/21.6.0".to_string(),
+            proxy: None,
+            bag_ttl: Duration::from_secs(60 * 60),
+            bag_cache_path: None,
+            bag_signing_roots: vec![
+                include_bytes!("../certs/root/profileidentity.ess.apple.com.cert").to_vec(),
+                include_bytes!("../certs/root/init.ess.apple.com.cert").to_vec(),
+            ],
+            verify_bags: true,
+        }
+    }
+}
+
+impl RustpushConfig {
+    fn build_client(&self) -> Result<Client, PushError> {
+        let mut builder = reqwest::Client::builder()
+            .use_rustls_tls()
+            .tls_built_in_root_certs(false)
+            .connect_timeout(self.connect_timeout);
+
+        for root in &self.pinned_roots {
+            builder = builder.add_root_certificate(Certificate::from_pem(root)?);
+        }
+
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.to_reqwest()?);
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+struct Shared {
+    config: Arc<RustpushConfig>,
+    // one client so connections are pooled across every request
+    client: Client,
+}
+
+lazy_static! {
+    static ref SHARED: RwLock<Shared> = {
+        let config = RustpushConfig::default();
+        let client = config.build_client().unwrap();
+        RwLock::new(Shared {
+            config: Arc::new(config),
+            client,
+        })
+    };
+}
+
+// applies to requests and connections started after this call
+pub fn set_config(config: RustpushConfig) -> Result<(), PushError> {
+    let client = config.build_client()?;
+    *SHARED.write().unwrap() = Shared {
+        config: Arc::new(config),
+        client,
+    };
+    Ok(())
+}
+
+pub fn get_config() -> Arc<RustpushConfig> {
+    SHARED.read().unwrap().config.clone()
+}
+
+// no overall timeout, so long MMCS transfers aren't cut off
+pub(crate) fn http_client() -> Client {
+    SHARED.read().unwrap().client.clone()
+}
+
+// a small API request, limited to `RustpushConfig::request_timeout` including the body
+pub(crate) fn api_request<U: IntoUrl>(method: Method, url: U) -> RequestBuilder {
+    let shared = SHARED.read().unwrap();
+    shared
+        .client
+        .request(method, url)
+        .timeout(shared.config.request_timeout)
+}
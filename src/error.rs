@@ -15,11 +15,29 @@ pub enum PushError {
     LookupFailed(u64),
     KeyError(KeyError),
     TwoFaError,
+    TwoFaCodeRejected,
+    NoStoredCredentials(String /* user id */),
+    AuthCertExpired(String /* user id */),
+    IdentityCertExpired(String /* user id */),
+    SRPError(String),
+    GSAError(i64 /* ec */, String /* em */),
     KeyNotFound(String),
     APNSConnectError,
+    MalformedAPNSPayload(String),
+    APNSTimeout,
+    APNSConnectionClosed,
+    APNSSubscriberOverflow(usize /* dropped */),
+    APNSSendError(u8 /* status */),
     TLSError(rustls::Error),
     StatusError(reqwest::StatusCode /* code */),
+    BagKeyNotFound(String),
+    BagSignatureError(String),
+    ProxyError(String),
     AlbertCertParseError,
+    AlbertStatusError(reqwest::StatusCode /* code */),
+    AlbertActivationRefused(String),
+    AlbertMissingCertificate,
+    AlbertMalformedCertificate(String),
 }
 
 impl Display for PushError {
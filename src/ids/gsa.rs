@@ -0,0 +1,589 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use log::{debug, info};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    hash::MessageDigest,
+    memcmp,
+    pkcs5::pbkdf2_hmac,
+    pkey::PKey,
+    sha::Sha256,
+    sign::Signer,
+    symm::{decrypt, Cipher},
+};
+use plist::{Dictionary, Value};
+use rand::Rng;
+use reqwest::{Method, RequestBuilder};
+use serde_json::json;
+
+use crate::{
+    config::{api_request, get_config},
+    util::{base64_encode, plist_to_string},
+    PushError,
+};
+
+use super::user::{
+    request_auth_token, AppleIDAuthenticator, AuthResponse, PendingLogin, SecondFactor,
+    TwoFactorCode,
+};
+
+// RFC 5054's 2048 bit group
+const N_HEX: &str = "AC6BDB41324A9A9BF166DE5E1389582FAF72B6651987EE07FC3192943DB56050A37329CBB4A099ED8193E0757767A13DD52312AB4B03310DCD7F48A9DA04FD50E8083969EDB767B0CF6095179A163AB3661A05FBD5FAAAE82918A9962F0B93B855F97993EC975EEAA80D740ADBF4FF747359D041D5C33EA71D281E446B14773BCA97B43A23FB801676BD207A436C6481F1D2B9078717461A5B9D32E688F87748544523B524B0D57D5EA77A2775D2ECFA032CFBDBF52FB3786160279004E57AE6AF874E7303CE53299CCC041C7BC308D82A5698F3A8D0C38271AE35F8E9DBFBB694B5C803D89F7AE435DE236D525F54759B65E372FCD68EF20FA7111F9E4AFF73";
+const G: u32 = 2;
+const N_LEN: i32 = 256;
+
+fn sha256(parts: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finish().to_vec()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, PushError> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    Ok(signer.sign_oneshot_to_vec(data)?)
+}
+
+// the client side of Apple's SRP-6a: SHA-256 over the 2048 bit group with RFC 5054
+// padding, and x = H(s | H(":" | p)), leaving the username out
+pub struct SrpClient {
+    n: BigNum,
+    g: BigNum,
+    a: BigNum,
+    a_pub: BigNum,
+    // (K, expected M2), once the challenge is processed
+    session: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl SrpClient {
+    pub fn new() -> Result<SrpClient, PushError> {
+        SrpClient::with_private(&rand::thread_rng().gen::<[u8; 32]>())
+    }
+
+    // fixed private value `a`, for reproducible exchanges
+    pub fn with_private(a: &[u8]) -> Result<SrpClient, PushError> {
+        let n = BigNum::from_hex_str(N_HEX)?;
+        let g = BigNum::from_u32(G)?;
+        let a = BigNum::from_slice(a)?;
+        let mut ctx = BigNumContext::new()?;
+        let mut a_pub = BigNum::new()?;
+        a_pub.mod_exp(&g, &a, &n, &mut ctx)?;
+        Ok(SrpClient {
+            n,
+            g,
+            a,
+            a_pub,
+            session: None,
+        })
+    }
+
+    // A, sent as A2k
+    pub fn public_key(&self) -> Vec<u8> {
+        self.a_pub.to_vec()
+    }
+
+    // the SRP password for `protocol`, s2k or s2k_fo as chosen by the server
+    pub fn password_key(
+        password: &str,
+        salt: &[u8],
+        iterations: usize,
+        protocol: &str,
+    ) -> Result<Vec<u8>, PushError> {
+        let hashed = sha256(&[password.as_bytes()]);
+        let hashed = match protocol {
+            "s2k" => hashed,
+            "s2k_fo" => hashed
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+                .into_bytes(),
+            _ => {
+                return Err(PushError::SRPError(format!(
+                    "unsupported protocol {}",
+                    protocol
+                )))
+            }
+        };
+        let mut key = vec![0; 32];
+        pbkdf2_hmac(&hashed, salt, iterations, MessageDigest::sha256(), &mut key)?;
+        Ok(key)
+    }
+
+    // takes the server's salt and B, returns our proof M1
+    pub fn process_challenge(
+        &mut self,
+        username: &str,
+        password_key: &[u8],
+        salt: &[u8],
+        b_pub: &[u8],
+    ) -> Result<Vec<u8>, PushError> {
+        let mut ctx = BigNumContext::new()?;
+        let n = &self.n;
+        let b_pub = BigNum::from_slice(b_pub)?;
+        let mut check = BigNum::new()?;
+        check.nnmod(&b_pub, n, &mut ctx)?;
+        if check.num_bits() == 0 {
+            return Err(PushError::SRPError("invalid server public key".to_string()));
+        }
+
+        let pad = |num: &BigNum| num.to_vec_padded(N_LEN);
+        let k = BigNum::from_slice(&sha256(&[&n.to_vec(), &pad(&self.g)?]))?;
+        let u = BigNum::from_slice(&sha256(&[&pad(&self.a_pub)?, &pad(&b_pub)?]))?;
+        if u.num_bits() == 0 {
+            return Err(PushError::SRPError("invalid server public key".to_string()));
+        }
+        let x = BigNum::from_slice(&sha256(&[salt, &sha256(&[b":", password_key])]))?;
+
+        // S = (B - k * g^x) ^ (a + u * x) mod N
+        let mut v = BigNum::new()?;
+        v.mod_exp(&self.g, &x, n, &mut ctx)?;
+        let mut kv = BigNum::new()?;
+        kv.mod_mul(&k, &v, n, &mut ctx)?;
+        let mut base = BigNum::new()?;
+        base.mod_sub(&b_pub, &kv, n, &mut ctx)?;
+        let mut ux = BigNum::new()?;
+        ux.checked_mul(&u, &x, &mut ctx)?;
+        let mut exponent = BigNum::new()?;
+        exponent.checked_add(&self.a, &ux)?;
+        let mut secret = BigNum::new()?;
+        secret.mod_exp(&base, &exponent, n, &mut ctx)?;
+        let session_key = sha256(&[&secret.to_vec()]);
+
+        let hn = sha256(&[&n.to_vec()]);
+        let hg = sha256(&[&pad(&self.g)?]);
+        let hn_xor_hg: Vec<u8> = hn.iter().zip(hg).map(|(a, b)| a ^ b).collect();
+        let a_pub = self.a_pub.to_vec();
+        let m1 = sha256(&[
+            &hn_xor_hg,
+            &sha256(&[username.as_bytes()]),
+            salt,
+            &a_pub,
+            &b_pub.to_vec(),
+            &session_key,
+        ]);
+        let m2 = sha256(&[&a_pub, &m1, &session_key]);
+        self.session = Some((session_key, m2));
+        Ok(m1)
+    }
+
+    // checks the server's proof M2
+    pub fn verify_server(&self, m2: &[u8]) -> Result<(), PushError> {
+        let Some((_, expected)) = &self.session else {
+            return Err(PushError::SRPError("challenge not processed".to_string()));
+        };
+        if m2.len() != expected.len() || !memcmp::eq(m2, expected) {
+            return Err(PushError::SRPError(
+                "server proof does not match".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // K, the shared session key
+    pub fn session_key(&self) -> Option<&[u8]> {
+        self.session.as_ref().map(|(key, _)| key.as_slice())
+    }
+
+    // the server's encrypted `spd`, keyed off the session key
+    fn decrypt_spd(&self, data: &[u8]) -> Result<Vec<u8>, PushError> {
+        let key = self
+            .session_key()
+            .ok_or_else(|| PushError::SRPError("challenge not processed".to_string()))?;
+        let aes_key = hmac_sha256(key, b"extra data key:")?;
+        let iv = hmac_sha256(key, b"extra data iv:")?;
+        Ok(decrypt(
+            Cipher::aes_256_cbc(),
+            &aes_key,
+            Some(&iv[..16]),
+            data,
+        )?)
+    }
+}
+
+// anisette headers (X-Apple-I-MD, X-Apple-I-MD-M, X-Mme-Device-Id...) vouching for this machine
+#[async_trait]
+pub trait AnisetteProvider: Send + Sync {
+    async fn anisette_headers(&self) -> Result<HashMap<String, String>, PushError>;
+}
+
+// what a finished SRP exchange gave us
+struct GSASession {
+    spd: Dictionary,
+    second_factor: Option<SecondFactor>,
+}
+
+// the SRP password key, with the parameters it was derived under. it logs in just like the
+// password does, so guard it the same way; it only spares us holding the plaintext
+#[derive(Clone)]
+struct PasswordKey {
+    protocol: String,
+    salt: Vec<u8>,
+    iterations: u64,
+    key: Vec<u8>,
+}
+
+// what `srp_login` proves we know
+enum Credential<'a> {
+    Password(&'a str),
+    Key(&'a PasswordKey),
+}
+
+impl Credential<'_> {
+    fn password_key(
+        &self,
+        protocol: &str,
+        salt: &[u8],
+        iterations: u64,
+    ) -> Result<PasswordKey, PushError> {
+        match self {
+            Credential::Password(password) => Ok(PasswordKey {
+                protocol: protocol.to_string(),
+                salt: salt.to_vec(),
+                iterations,
+                key: SrpClient::password_key(password, salt, iterations as usize, protocol)?,
+            }),
+            Credential::Key(key) => {
+                if key.protocol != protocol || key.salt != salt || key.iterations != iterations {
+                    // the password was changed in the meantime
+                    return Err(PushError::SRPError(
+                        "password parameters changed, log in again".to_string(),
+                    ));
+                }
+                Ok((*key).clone())
+            }
+        }
+    }
+}
+
+const PLIST_HEADER: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n";
+
+// a code that was wrong or expired
+const GSA_BAD_CODE: i64 = -21669;
+
+fn check_status(response: &Dictionary) -> Result<(), PushError> {
+    let status = response
+        .get("Status")
+        .and_then(|v| v.as_dictionary())
+        .unwrap_or(response);
+    let code = status
+        .get("ec")
+        .and_then(|v| v.as_signed_integer())
+        .unwrap_or(0);
+    if code != 0 {
+        let message = status
+            .get("em")
+            .and_then(|v| v.as_string())
+            .unwrap_or_default();
+        return Err(PushError::GSAError(code, message.to_string()));
+    }
+    Ok(())
+}
+
+fn get_data<'a>(dict: &'a Dictionary, key: &str) -> Result<&'a [u8], PushError> {
+    dict.get(key)
+        .and_then(|v| v.as_data())
+        .ok_or_else(|| PushError::GSAError(0, format!("missing {}", key)))
+}
+
+fn get_str<'a>(dict: &'a Dictionary, key: &str) -> Result<&'a str, PushError> {
+    dict.get(key)
+        .and_then(|v| v.as_string())
+        .ok_or_else(|| PushError::GSAError(0, format!("missing {}", key)))
+}
+
+// logs in with GrandSlam's SRP exchange, so the password never leaves this machine, then
+// trades the resulting PET for an IDS auth token. gsa.apple.com chains to the Apple Server
+// Authentication CA, which is already in the default pinned roots
+#[derive(Clone)]
+pub struct GSAAuthenticator {
+    anisette: Arc<dyn AnisetteProvider>,
+}
+
+impl GSAAuthenticator {
+    pub fn new(anisette: impl AnisetteProvider + 'static) -> GSAAuthenticator {
+        GSAAuthenticator {
+            anisette: Arc::new(anisette),
+        }
+    }
+
+    fn client_info() -> String {
+        let device = &get_config().device.ids;
+        format!(
+            "<{}> <Mac OS X;{};{}> <com.apple.AOSKit/282 (com.apple.dt.Xcode/3594.4.19)>",
+            device.product_type, device.product_version, device.build_version
+        )
+    }
+
+    // one GsService2 call, returns the "Response" dictionary
+    async fn request(&self, mut parameters: Dictionary) -> Result<Dictionary, PushError> {
+        let mut cpd = Dictionary::from_iter([
+            ("bootstrap", Value::Boolean(true)),
+            ("icscrec", Value::Boolean(true)),
+            ("pbe", Value::Boolean(false)),
+            ("prkgen", Value::Boolean(true)),
+            ("svct", Value::String("iCloud".to_string())),
+        ]);
+        for (key, value) in self.anisette.anisette_headers().await? {
+            cpd.insert(key, Value::String(value));
+        }
+        parameters.insert("cpd".to_string(), Value::Dictionary(cpd));
+        let body = Value::Dictionary(Dictionary::from_iter([
+            (
+                "Header",
+                Value::Dictionary(Dictionary::from_iter([(
+                    "Version",
+                    Value::String("1.0.1".to_string()),
+                )])),
+            ),
+            ("Request", Value::Dictionary(parameters)),
+        ]));
+
+        let resp = api_request(
+            Method::POST,
+            format!("{}/grandslam/GsService2", get_config().endpoints.gsa),
+        )
+        .header("Content-Type", "text/x-xml-plist")
+        .header("Accept", "*/*")
+        .header("User-Agent", "akd/1.0 CFNetwork/978.0.7 Darwin/18.7.0")
+        .header("X-MMe-Client-Info", GSAAuthenticator::client_info())
+        .body(plist_to_string(&body)?)
+        .send()
+        .await?;
+        if !resp.status().is_success() {
+            return Err(PushError::StatusError(resp.status()));
+        }
+        let data = resp.bytes().await?;
+        let response: Dictionary = plist::from_bytes(&data)?;
+        let response = response
+            .get("Response")
+            .and_then(|v| v.as_dictionary())
+            .ok_or_else(|| PushError::GSAError(0, "missing Response".to_string()))?;
+        check_status(response)?;
+        Ok(response.clone())
+    }
+
+    async fn srp_login(
+        &self,
+        username: &str,
+        credential: Credential<'_>,
+    ) -> Result<(GSASession, PasswordKey), PushError> {
+        let mut srp = SrpClient::new()?;
+        let init = self
+            .request(Dictionary::from_iter([
+                ("A2k", Value::Data(srp.public_key())),
+                ("ps", Value::Array(vec!["s2k".into(), "s2k_fo".into()])),
+                ("u", Value::String(username.to_string())),
+                ("o", Value::String("init".to_string())),
+            ]))
+            .await?;
+
+        let salt = get_data(&init, "s")?;
+        let iterations = init
+            .get("i")
+            .and_then(|v| v.as_unsigned_integer())
+            .ok_or_else(|| PushError::GSAError(0, "missing i".to_string()))?;
+        let password_key = credential.password_key(get_str(&init, "sp")?, salt, iterations)?;
+        let m1 = srp.process_challenge(username, &password_key.key, salt, get_data(&init, "B")?)?;
+
+        let complete = self
+            .request(Dictionary::from_iter([
+                (
+                    "c",
+                    init.get("c")
+                        .cloned()
+                        .unwrap_or(Value::String(String::new())),
+                ),
+                ("M1", Value::Data(m1)),
+                ("u", Value::String(username.to_string())),
+                ("o", Value::String("complete".to_string())),
+            ]))
+            .await?;
+        srp.verify_server(get_data(&complete, "M2")?)?;
+
+        let spd = srp.decrypt_spd(get_data(&complete, "spd")?)?;
+        let spd = [PLIST_HEADER.as_bytes(), &spd].concat();
+        let spd: Dictionary = plist::from_bytes(&spd)?;
+
+        let second_factor = match complete
+            .get("Status")
+            .and_then(|v| v.as_dictionary())
+            .and_then(|status| status.get("au"))
+            .and_then(|v| v.as_string())
+        {
+            Some("trustedDeviceSecondaryAuth") => Some(SecondFactor::TrustedDevice),
+            Some("secondaryAuth") => Some(SecondFactor::Sms),
+            _ => None,
+        };
+        Ok((GSASession { spd, second_factor }, password_key))
+    }
+
+    // a request to the 2FA endpoints, authenticated with the SRP session
+    async fn second_factor_request(
+        &self,
+        session: &GSASession,
+        method: Method,
+        path: &str,
+    ) -> Result<RequestBuilder, PushError> {
+        let identity = format!(
+            "{}:{}",
+            get_str(&session.spd, "adsid")?,
+            get_str(&session.spd, "GsIdmsToken")?
+        );
+        let mut request = api_request(method, format!("{}{}", get_config().endpoints.gsa, path))
+            .header("Content-Type", "text/x-xml-plist")
+            .header("Accept", "text/x-xml-plist")
+            .header("Accept-Language", "en-us")
+            .header("User-Agent", "Xcode")
+            .header("X-MMe-Client-Info", GSAAuthenticator::client_info())
+            .header("X-Apple-Identity-Token", base64_encode(identity.as_bytes()));
+        for (key, value) in self.anisette.anisette_headers().await? {
+            request = request.header(key, value);
+        }
+        Ok(request)
+    }
+
+    // asks Apple to send the user a code; SMS goes to the first trusted number
+    async fn request_code(&self, session: &GSASession, via: SecondFactor) -> Result<(), PushError> {
+        let request = match via {
+            SecondFactor::TrustedDevice => {
+                self.second_factor_request(session, Method::GET, "/auth/verify/trusteddevice")
+                    .await?
+            }
+            SecondFactor::Sms => self
+                .second_factor_request(session, Method::PUT, "/auth/verify/phone/")
+                .await?
+                .json(&json!({ "phoneNumber": { "id": 1 }, "mode": "sms" })),
+        };
+        let resp = request.send().await?;
+        if !resp.status().is_success() {
+            return Err(PushError::StatusError(resp.status()));
+        }
+        Ok(())
+    }
+
+    async fn validate_code(
+        &self,
+        session: &GSASession,
+        code: &TwoFactorCode,
+    ) -> Result<(), PushError> {
+        match code {
+            TwoFactorCode::TrustedDevice(code) => {
+                let resp = self
+                    .second_factor_request(session, Method::GET, "/grandslam/GsService2/validate")
+                    .await?
+                    .header("security-code", code.trim())
+                    .send()
+                    .await?;
+                let data = resp.bytes().await?;
+                let response: Dictionary = plist::from_bytes(&data)?;
+                match check_status(&response) {
+                    Err(PushError::GSAError(GSA_BAD_CODE, _)) => Err(PushError::TwoFaCodeRejected),
+                    result => result,
+                }
+            }
+            TwoFactorCode::Sms(code) => {
+                let resp = self
+                    .second_factor_request(session, Method::POST, "/auth/verify/phone/securitycode")
+                    .await?
+                    .json(&json!({
+                        "phoneNumber": { "id": 1 },
+                        "securityCode": { "code": code.trim() },
+                        "mode": "sms",
+                    }))
+                    .send()
+                    .await?;
+                if !resp.status().is_success() {
+                    debug!("SMS code rejected with {}", resp.status());
+                    return Err(PushError::TwoFaCodeRejected);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // trades the password equivalent token for an IDS auth token
+    async fn exchange_pet(
+        &self,
+        username: &str,
+        session: &GSASession,
+    ) -> Result<(String, String), PushError> {
+        let pet = session
+            .spd
+            .get("t")
+            .and_then(|v| v.as_dictionary())
+            .and_then(|tokens| tokens.get("com.apple.gs.idms.pet"))
+            .and_then(|v| v.as_dictionary())
+            .and_then(|pet| pet.get("token"))
+            .and_then(|v| v.as_string())
+            .ok_or_else(|| PushError::GSAError(0, "missing PET".to_string()))?;
+        info!("Got PET from GSA");
+        request_auth_token(username, pet).await
+    }
+}
+
+#[async_trait]
+impl AppleIDAuthenticator for GSAAuthenticator {
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<AuthResponse, PushError> {
+        let (session, password_key) = self
+            .srp_login(username, Credential::Password(password))
+            .await?;
+        let Some(second_factor) = session.second_factor else {
+            let (token, user_id) = self.exchange_pet(username, &session).await?;
+            return Ok(AuthResponse::Token { token, user_id });
+        };
+        self.request_code(&session, second_factor).await?;
+        Ok(AuthResponse::NeedsTwoFactor(Box::new(GSAPendingLogin {
+            gsa: self.clone(),
+            username: username.to_string(),
+            session,
+            password_key,
+            second_factor,
+        })))
+    }
+}
+
+// a GSA login waiting on a code, authenticated by the first exchange's identity token. holds
+// the password key to log in again once the code is accepted, so it's as sensitive as the
+// password until dropped
+struct GSAPendingLogin {
+    gsa: GSAAuthenticator,
+    username: String,
+    session: GSASession,
+    password_key: PasswordKey,
+    second_factor: SecondFactor,
+}
+
+#[async_trait]
+impl PendingLogin for GSAPendingLogin {
+    fn second_factor(&self) -> SecondFactor {
+        self.second_factor
+    }
+
+    async fn request_sms(&mut self) -> Result<(), PushError> {
+        self.gsa
+            .request_code(&self.session, SecondFactor::Sms)
+            .await?;
+        self.second_factor = SecondFactor::Sms;
+        Ok(())
+    }
+
+    async fn submit_code(&self, code: &TwoFactorCode) -> Result<(String, String), PushError> {
+        self.gsa.validate_code(&self.session, code).await?;
+        // tokens are only issued on a fresh login once the code went through
+        let (session, _) = self
+            .gsa
+            .srp_login(&self.username, Credential::Key(&self.password_key))
+            .await?;
+        if session.second_factor.is_some() {
+            return Err(PushError::TwoFaCodeRejected);
+        }
+        self.gsa.exchange_pet(&self.username, &session).await
+    }
+}
@@ -1,4 +1,6 @@
-use std::{io::Cursor, sync::Arc};
+use std::{io::Cursor, sync::Arc, time::Duration};
+
+use log::info;
 
 use openssl::{
     bn::{BigNum, BigNumContext},
@@ -11,11 +13,13 @@ use openssl::{
     sign::{Signer, Verifier},
 };
 use plist::{Dictionary, Value};
+use reqwest::Method;
 
 use crate::{
     apns::APNSConnection,
+    config::{api_request, get_config},
     error::PushError,
-    util::{base64_decode, make_reqwest, plist_to_string, KeyPair},
+    util::{base64_decode, plist_to_string, KeyPair},
 };
 
 use super::{
@@ -182,11 +186,38 @@ impl IDSIdentity {
     }
 }
 
+// reauthenticates users whose auth certs expire within `margin`, then registers everyone
+// again if any identity cert does too. returns whether anything changed, so it can be saved
+pub async fn refresh_users(
+    valid_ctx: &str,
+    users: &mut [IDSUser],
+    conn: Arc<APNSConnection>,
+    margin: Duration,
+) -> Result<bool, PushError> {
+    let mut changed = false;
+    for user in users.iter_mut() {
+        if user.needs_reauth(margin) {
+            info!("Auth cert for {} expires soon, reauthenticating", user.user_id);
+            user.reauthenticate(conn.clone()).await?;
+            changed = true;
+        }
+    }
+    if users.iter().any(|user| user.needs_reregister(margin)) {
+        info!("Identity cert expires soon, registering again");
+        register(valid_ctx, users, conn).await?;
+        changed = true;
+    }
+    Ok(changed)
+}
+
 pub async fn register(
     valid_ctx: &str,
     users: &mut [IDSUser],
     conn: Arc<APNSConnection>,
 ) -> Result<(), PushError> {
+    if let Some(user) = users.iter().find(|user| user.needs_reauth(Duration::ZERO)) {
+        return Err(PushError::AuthCertExpired(user.user_id.clone()));
+    }
     let mut user_payloads: Vec<Value> = vec![];
     for user in users.iter_mut() {
         user.handles = user.possible_handles(conn.clone()).await?;
@@ -276,18 +307,17 @@ pub async fn register(
         user_payloads.push(Value::Dictionary(dict));
     }
 
+    let config = get_config();
+    let device = &config.device.ids;
     let body = Value::Dictionary(Dictionary::from_iter(
         [
             (
                 "hardware-version",
-                Value::String("MacBookPro18,3".to_string()),
+                Value::String(device.product_type.clone()),
             ),
             ("language", Value::String("en-US".to_string())),
-            (
-                "os-version",
-                Value::String("macOS,13.2.1,22D68".to_string()),
-            ),
-            ("software-version", Value::String("22D68".to_string())),
+            ("os-version", Value::String(device.os_version())),
+            ("software-version", Value::String(device.build_version.clone())),
             (
                 "services",
                 Value::Array(vec![Value::Dictionary(Dictionary::from_iter(
@@ -315,10 +345,7 @@ pub async fn register(
     ));
 
     let body = plist_to_string(&body)?;
-    let client = make_reqwest();
-
-    let mut builder = client
-        .get("https://identity.ess.apple.com/WebObjects/TDIdentityService.woa/wa/register")
+    let mut builder = api_request(Method::GET, &config.endpoints.ids_register)
         .header("x-protocol-version", "1640");
     for (idx, user) in users.iter().enumerate() {
         builder = auth_sign_req(
@@ -326,7 +353,7 @@ pub async fn register(
             body.as_bytes(),
             "id-register",
             &user.auth_keypair,
-            &conn.state,
+            &conn.state(),
             Some(idx as u8),
         )?;
     }
@@ -1,3 +1,5 @@
+pub mod gsa;
 pub mod identity;
+pub mod scheduler;
 pub mod signing;
 pub mod user;
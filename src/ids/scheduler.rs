@@ -0,0 +1,269 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use tokio::{
+    sync::{broadcast, watch},
+    task::JoinHandle,
+    time::sleep,
+};
+
+use crate::{
+    apns::{APNSConnection, APNSConnectionState},
+    error::PushError,
+};
+
+use super::{identity::register, user::IDSUser};
+
+// IDS wants fresh validation data for every registration
+#[async_trait]
+pub trait ValidationDataProvider: Send + Sync {
+    // base64, as `register` takes it
+    async fn validation_data(&self) -> Result<String, PushError>;
+}
+
+// when the scheduler re-registers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegistrationPolicy {
+    // how long before the auth or identity cert expires to replace it
+    pub margin: Duration,
+    // how often to ask IDS whether a user's handles changed
+    pub handle_check_interval: Duration,
+    // wait after a failed attempt
+    pub retry: Duration,
+}
+
+impl Default for RegistrationPolicy {
+    fn default() -> Self {
+        RegistrationPolicy {
+            margin: Duration::from_secs(24 * 60 * 60),
+            handle_check_interval: Duration::from_secs(6 * 60 * 60),
+            retry: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegistrationEvent {
+    // a user got a new auth cert, save `users()`
+    Reauthenticated {
+        user_id: String,
+    },
+    // every user was registered again, save `users()`
+    Registered,
+    // IDS reports different handles than we registered
+    HandlesChanged {
+        user_id: String,
+        handles: Vec<String>,
+    },
+    // we'll try again after `RegistrationPolicy::retry`
+    Failed(String),
+}
+
+// keeps users registered with IDS until the connection shuts down
+pub struct RegistrationScheduler {
+    users: Arc<watch::Sender<Arc<Vec<IDSUser>>>>,
+    events: broadcast::Sender<RegistrationEvent>,
+    handle: JoinHandle<()>,
+}
+
+impl RegistrationScheduler {
+    pub fn start(
+        conn: Arc<APNSConnection>,
+        users: Vec<IDSUser>,
+        validation: Arc<dyn ValidationDataProvider>,
+        policy: RegistrationPolicy,
+    ) -> RegistrationScheduler {
+        let users = Arc::new(watch::channel(Arc::new(users)).0);
+        let (events, _) = broadcast::channel(16);
+        let task = SchedulerTask {
+            conn,
+            users: users.clone(),
+            events: events.clone(),
+            validation,
+            policy,
+        };
+        RegistrationScheduler {
+            users,
+            events,
+            handle: tokio::spawn(task.run()),
+        }
+    }
+
+    // current users, with the latest auth and identity certs
+    pub fn users(&self) -> Arc<Vec<IDSUser>> {
+        self.users.borrow().clone()
+    }
+
+    // changes after every reauthentication or registration, so saved users can be updated
+    pub fn watch_users(&self) -> watch::Receiver<Arc<Vec<IDSUser>>> {
+        self.users.subscribe()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RegistrationEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn stop(&self) {
+        self.handle.abort();
+    }
+}
+
+impl Drop for RegistrationScheduler {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+struct SchedulerTask {
+    conn: Arc<APNSConnection>,
+    users: Arc<watch::Sender<Arc<Vec<IDSUser>>>>,
+    events: broadcast::Sender<RegistrationEvent>,
+    validation: Arc<dyn ValidationDataProvider>,
+    policy: RegistrationPolicy,
+}
+
+impl SchedulerTask {
+    fn emit(&self, event: RegistrationEvent) {
+        // nobody listening is fine
+        let _ = self.events.send(event);
+    }
+
+    // earliest time a cert needs replacing, certs we can't read are left alone
+    fn next_deadline(&self) -> Option<SystemTime> {
+        let margin = self.policy.margin;
+        let replace_at = |expiry: SystemTime| expiry.checked_sub(margin).unwrap_or(UNIX_EPOCH);
+        self.users
+            .borrow()
+            .iter()
+            .flat_map(|user| {
+                let identity = match user.identity_cert_expiry() {
+                    Ok(Some(expiry)) => Some(replace_at(expiry)),
+                    // unregistered, right away
+                    Ok(None) => Some(UNIX_EPOCH),
+                    Err(_) => None,
+                };
+                [user.auth_cert_expiry().ok().map(replace_at), identity]
+            })
+            .flatten()
+            .min()
+    }
+
+    async fn run(self) {
+        let mut conn_state = self.conn.watch_connection_state();
+        // users are taken to be registered with the token we start with
+        let mut registered_token = self.conn.state().token;
+        let mut check_handles_at = SystemTime::now() + self.policy.handle_check_interval;
+        let mut force = false;
+        loop {
+            let token = match &*conn_state.borrow_and_update() {
+                APNSConnectionState::Shutdown => return,
+                APNSConnectionState::Connected { token } => Some(token.clone()),
+                _ => None,
+            };
+            // registering needs a token, wait for the connection
+            let Some(token) = token else {
+                if conn_state.changed().await.is_err() {
+                    return;
+                }
+                continue;
+            };
+            if registered_token.as_ref() != Some(&token) {
+                info!("push token changed, registering again");
+                force = true;
+            }
+
+            let now = SystemTime::now();
+            if now >= check_handles_at {
+                check_handles_at = now + self.policy.handle_check_interval;
+                match self.handles_changed().await {
+                    Ok(changed) => force |= changed,
+                    Err(err) => {
+                        warn!("failed to check handles: {:?}", err);
+                        self.emit(RegistrationEvent::Failed(format!("{:?}", err)));
+                    }
+                }
+            }
+
+            match self.refresh(force).await {
+                Ok(true) => {
+                    registered_token = Some(token);
+                    force = false;
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    warn!("failed to refresh IDS registration: {:?}", err);
+                    self.emit(RegistrationEvent::Failed(format!("{:?}", err)));
+                    tokio::select! {
+                        _ = sleep(self.policy.retry) => {}
+                        result = conn_state.changed() => if result.is_err() { return },
+                    }
+                    continue;
+                }
+            }
+
+            let wake_at = match self.next_deadline() {
+                Some(deadline) => deadline.min(check_handles_at),
+                None => check_handles_at,
+            };
+            // a cert still due right after refreshing would have us spin, back off instead
+            let wait = wake_at
+                .duration_since(SystemTime::now())
+                .unwrap_or(self.policy.retry);
+            debug!("checking IDS registration in {:?}", wait);
+            tokio::select! {
+                _ = sleep(wait) => {}
+                // shutdown or a new token
+                result = conn_state.changed() => if result.is_err() { return },
+            }
+        }
+    }
+
+    // true if any user's handles differ from the ones they were registered with
+    async fn handles_changed(&self) -> Result<bool, PushError> {
+        let users = self.users.borrow().clone();
+        let mut changed = false;
+        for user in users.iter() {
+            let handles = user.possible_handles(self.conn.clone()).await?;
+            if handles != user.handles {
+                info!("handles for {} changed to {:?}", user.user_id, handles);
+                self.emit(RegistrationEvent::HandlesChanged {
+                    user_id: user.user_id.clone(),
+                    handles,
+                });
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+
+    // reauthenticates and registers whoever needs it, returns whether we registered
+    async fn refresh(&self, force: bool) -> Result<bool, PushError> {
+        let margin = self.policy.margin;
+        let mut users = (**self.users.borrow()).clone();
+
+        for i in 0..users.len() {
+            if !users[i].needs_reauth(margin) {
+                continue;
+            }
+            let user_id = users[i].user_id.clone();
+            info!("Auth cert for {} expires soon, reauthenticating", user_id);
+            users[i].reauthenticate(self.conn.clone()).await?;
+            // saved right away, so a failed registration doesn't lose it
+            self.users.send_replace(Arc::new(users.clone()));
+            self.emit(RegistrationEvent::Reauthenticated { user_id });
+        }
+
+        if !force && !users.iter().any(|user| user.needs_reregister(margin)) {
+            return Ok(false);
+        }
+        let valid_ctx = self.validation.validation_data().await?;
+        register(&valid_ctx, &mut users, self.conn.clone()).await?;
+        self.users.send_replace(Arc::new(users));
+        self.emit(RegistrationEvent::Registered);
+        Ok(true)
+    }
+}
@@ -1,12 +1,19 @@
-use std::{collections::HashMap, io::Cursor, sync::Arc};
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use crate::{
     apns::{APNSConnection, APNSState},
-    bags::{get_bag, IDS_BAG},
+    bags::get_ids_bag,
+    config::{api_request, get_config},
     error::PushError,
     ids::signing::auth_sign_req,
-    util::{gzip, make_reqwest, plist_to_bin, plist_to_string, ungzip, KeyPair},
+    util::{cert_validity, gzip, plist_to_bin, plist_to_string, ungzip, KeyPair},
 };
+use async_trait::async_trait;
 use log::info;
 use openssl::{
     bn::BigNum,
@@ -17,6 +24,7 @@ use openssl::{
     x509::{X509NameBuilder, X509ReqBuilder},
 };
 use plist::{Data, Dictionary, Value};
+use reqwest::Method;
 use rand::Rng;
 use serde::Deserialize;
 use serde::Serialize;
@@ -37,9 +45,7 @@ async fn attempt_auth(username: &str, password: &str) -> Result<Value, PushError
         password: password.to_string(),
     };
 
-    let client = make_reqwest();
-    let resp = client
-        .post("https://profile.ess.apple.com/WebObjects/VCProfileService.woa/wa/authenticateUser")
+    let resp = api_request(Method::POST, &get_config().endpoints.ids_authenticate)
         .body(plist_to_string(&request)?)
         .send()
         .await?;
@@ -49,31 +55,79 @@ async fn attempt_auth(username: &str, password: &str) -> Result<Value, PushError
     Ok(response)
 }
 
-async fn get_auth_token(username: &str, password: &str) -> Result<(String, String), PushError> {
-    let result = attempt_auth(username, password).await?;
-    // attempt 2fa
-    let result_dict = result.as_dictionary().unwrap();
-    if result_dict
-        .get("status")
-        .unwrap()
-        .as_unsigned_integer()
-        .unwrap()
-        == 5000
-    {
-        return Err(PushError::TwoFaError);
+// where a 2FA code is sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SecondFactor {
+    TrustedDevice,
+    Sms,
+}
+
+// the outcome of exchanging Apple ID credentials for an IDS auth token
+pub enum AuthResponse {
+    Token { token: String, user_id: String },
+    // a code was sent to the user, finish through the pending login
+    NeedsTwoFactor(Box<dyn PendingLogin>),
+}
+
+// how Apple ID credentials are turned into an IDS auth token
+#[async_trait]
+pub trait AppleIDAuthenticator: Send + Sync {
+    async fn authenticate(&self, username: &str, password: &str)
+        -> Result<AuthResponse, PushError>;
+}
+
+// a login held open by the server until the user's 2FA code comes in. an implementation may
+// keep what it needs to log in again after the code (GSA keeps the SRP password key, which
+// works just like the password), so don't persist or hand it around more than the password
+#[async_trait]
+pub trait PendingLogin: Send + Sync {
+    // where the code went
+    fn second_factor(&self) -> SecondFactor;
+
+    // sends a code by SMS instead, for users without a trusted device at hand
+    async fn request_sms(&mut self) -> Result<(), PushError>;
+
+    // (auth token, profile id); on TwoFaCodeRejected another code can be tried
+    async fn submit_code(&self, code: &TwoFactorCode) -> Result<(String, String), PushError>;
+}
+
+// posts the password to IDS's authenticateUser. it can't hold a login open for a code, so 2FA
+// accounts fail with TwoFaError unless the code is appended to the password
+pub struct ProfileAuthenticator;
+
+#[async_trait]
+impl AppleIDAuthenticator for ProfileAuthenticator {
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<AuthResponse, PushError> {
+        let (token, user_id) = request_auth_token(username, password).await?;
+        Ok(AuthResponse::Token { token, user_id })
     }
-    if result_dict
-        .get("status")
-        .unwrap()
-        .as_unsigned_integer()
-        .unwrap()
-        != 0
-    {
-        return Err(PushError::AuthError(result.clone()));
+}
+
+// (auth token, profile id)
+pub(super) async fn request_auth_token(
+    username: &str,
+    password: &str,
+) -> Result<(String, String), PushError> {
+    let result = attempt_auth(username, password).await?;
+    let result_dict = result
+        .as_dictionary()
+        .ok_or_else(|| PushError::AuthError(result.clone()))?;
+    match result_dict.get("status").and_then(|s| s.as_unsigned_integer()) {
+        Some(0) => {}
+        Some(5000) => return Err(PushError::TwoFaError),
+        _ => return Err(PushError::AuthError(result.clone())),
     }
 
-    let token = result_dict.get("auth-token").unwrap().as_string().unwrap();
-    let user_id = result_dict.get("profile-id").unwrap().as_string().unwrap();
+    let (Some(token), Some(user_id)) = (
+        result_dict.get("auth-token").and_then(|v| v.as_string()),
+        result_dict.get("profile-id").and_then(|v| v.as_string()),
+    ) else {
+        return Err(PushError::AuthError(result.clone()));
+    };
 
     info!("Got auth token for IDS {}", token);
     Ok((token.to_string(), user_id.to_string()))
@@ -134,10 +188,8 @@ async fn authenticate(
         realm_user_id: user_id.to_string(),
     };
 
-    let ids_bag = get_bag(IDS_BAG).await?;
-    let client = make_reqwest();
-    let resp = client
-        .post(ids_bag.get(endpoint_key).unwrap().as_string().unwrap())
+    let ids_bag = get_ids_bag().await?;
+    let resp = api_request(Method::POST, ids_bag.get_str(endpoint_key)?)
         .header("x-protocol-version", "1630")
         .body(plist_to_string(&body)?)
         .send()
@@ -206,11 +258,9 @@ pub async fn get_handles(
     auth_keypair: &KeyPair,
     push_state: &APNSState,
 ) -> Result<Vec<String>, PushError> {
-    let ids_bag = get_bag(IDS_BAG).await?;
-    let client = make_reqwest();
+    let ids_bag = get_ids_bag().await?;
     let resp = auth_sign_req(
-        client
-            .get(ids_bag.get("id-get-handles").unwrap().as_string().unwrap())
+        api_request(Method::GET, ids_bag.id_get_handles()?)
             .header("x-protocol-version", "1640")
             .header("x-auth-user-id", user_id),
         &[],
@@ -243,10 +293,45 @@ pub struct IDSUser {
     pub handles: Vec<String>, // usable handles
     pub identity: Option<IDSIdentity>,
     pub user_type: IDSUserType,
+    // how to get a new auth cert, missing for users saved before we kept it
+    #[serde(default)]
+    pub auth: Option<IDSUserAuth>,
+}
+
+// gets a user a new auth cert without asking them for anything again
+#[async_trait]
+pub trait IDSAuthenticator: Send + Sync {
+    async fn reauthenticate(&self, conn: &APNSConnection) -> Result<KeyPair, PushError>;
+}
+
+// an Apple ID user, kept as their IDS auth token
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IDSAppleUser {
+    user_id: String,
+    auth_token: String,
+}
+
+// a phone number user, kept as the signature from SMS registration
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IDSPhoneUser {
+    phone_number: String,
+    phone_sig: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum IDSUserAuth {
+    Apple(IDSAppleUser),
+    Phone(IDSPhoneUser),
 }
 
-pub struct IDSAppleUser;
-pub struct IDSPhoneUser;
+impl IDSUserAuth {
+    pub fn authenticator(&self) -> &dyn IDSAuthenticator {
+        match self {
+            IDSUserAuth::Apple(user) => user,
+            IDSUserAuth::Phone(user) => user,
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 struct LookupReq {
@@ -283,12 +368,52 @@ pub struct IDSIdentityResult {
 }
 
 impl IDSUser {
+    pub fn auth_cert_expiry(&self) -> Result<SystemTime, PushError> {
+        Ok(cert_validity(&self.auth_keypair.cert)?.1)
+    }
+
+    // None until registered
+    pub fn identity_cert_expiry(&self) -> Result<Option<SystemTime>, PushError> {
+        let Some(id_keypair) = self.identity.as_ref().and_then(|i| i.id_keypair.as_ref()) else {
+            return Ok(None);
+        };
+        Ok(Some(cert_validity(&id_keypair.cert)?.1))
+    }
+
+    // true if the auth cert expires within `margin`; false if we can't tell
+    pub fn needs_reauth(&self, margin: Duration) -> bool {
+        self.auth_cert_expiry()
+            .is_ok_and(|expiry| expiry <= SystemTime::now() + margin)
+    }
+
+    // true if unregistered, or the identity cert expires within `margin`
+    pub fn needs_reregister(&self, margin: Duration) -> bool {
+        match self.identity_cert_expiry() {
+            Ok(None) => true,
+            Ok(Some(expiry)) => expiry <= SystemTime::now() + margin,
+            Err(_) => false,
+        }
+    }
+
+    // replaces the auth cert, whichever kind of user this is
+    pub async fn reauthenticate(&mut self, conn: Arc<APNSConnection>) -> Result<(), PushError> {
+        let Some(auth) = &self.auth else {
+            return Err(PushError::NoStoredCredentials(self.user_id.clone()));
+        };
+        self.auth_keypair = auth.authenticator().reauthenticate(&conn).await?;
+        info!("Reauthenticated {}", self.user_id);
+        Ok(())
+    }
+
     // possible handles, which may have changed since registration
     pub async fn possible_handles(
         &self,
         conn: Arc<APNSConnection>,
     ) -> Result<Vec<String>, PushError> {
-        get_handles(&self.user_id, &self.auth_keypair, &conn.state).await
+        if self.needs_reauth(Duration::ZERO) {
+            return Err(PushError::AuthCertExpired(self.user_id.clone()));
+        }
+        get_handles(&self.user_id, &self.auth_keypair, &conn.state()).await
     }
 
     pub async fn lookup(
@@ -296,6 +421,9 @@ impl IDSUser {
         conn: Arc<APNSConnection>,
         query: Vec<String>,
     ) -> Result<HashMap<String, Vec<IDSIdentityResult>>, PushError> {
+        if self.identity.is_some() && self.needs_reregister(Duration::ZERO) {
+            return Err(PushError::IdentityCertExpired(self.user_id.clone()));
+        }
         let body = plist_to_string(&LookupReq { uris: query })?;
 
         // gzip encode
@@ -315,11 +443,11 @@ impl IDSUser {
             &encoded,
             "id-query",
             self.identity.as_ref().unwrap().id_keypair.as_ref().unwrap(),
-            &conn.state.token.as_ref().unwrap(),
+            &conn.state().token.as_ref().unwrap(),
         )?;
 
         let msg_id = rand::thread_rng().gen::<[u8; 16]>();
-        let ids_bag = get_bag(IDS_BAG).await?;
+        let ids_bag = get_ids_bag().await?;
 
         let request = Value::Dictionary(Dictionary::from_iter(
             [
@@ -328,7 +456,7 @@ impl IDSUser {
                 ("c", 96.into()),
                 (
                     "u",
-                    ids_bag.get("id-query").unwrap().as_string().unwrap().into(),
+                    ids_bag.id_query()?.into(),
                 ),
                 ("h", headers.into()),
                 ("v", 2.into()),
@@ -336,23 +464,21 @@ impl IDSUser {
             ]
             .into_iter(),
         ));
-        conn.send_message("com.apple.madrid", &plist_to_bin(&request)?, None)
+        let loaded = conn
+            .send_message_for_reply(
+                "com.apple.madrid",
+                &plist_to_bin(&request)?,
+                None,
+                move |loaded| {
+                    let Some(resp_id) = loaded.as_dictionary().unwrap().get("U") else {
+                        return false;
+                    };
+                    let resp_id = resp_id.as_data().unwrap();
+                    resp_id == msg_id
+                },
+            )
             .await?;
 
-        let response = conn
-            .reader
-            .wait_find_msg(move |loaded| {
-                let Some(resp_id) = loaded.as_dictionary().unwrap().get("U") else {
-                    return false;
-                };
-                let resp_id = resp_id.as_data().unwrap();
-                resp_id == msg_id
-            })
-            .await;
-
-        let data = response.get_field(3).unwrap();
-        let loaded: Value = plist::from_bytes(data).unwrap();
-
         // gzip decode
         let decoded_data = ungzip(
             loaded
@@ -400,25 +526,118 @@ impl IDSUser {
     }
 }
 
+// a 2FA code, and where it was delivered
+#[derive(Debug, Clone, PartialEq)]
+pub enum TwoFactorCode {
+    TrustedDevice(String),
+    Sms(String),
+}
+
+impl TwoFactorCode {
+    pub fn code(&self) -> &str {
+        match self {
+            TwoFactorCode::TrustedDevice(code) | TwoFactorCode::Sms(code) => code.trim(),
+        }
+    }
+}
+
+// a login waiting on a 2FA code. it holds the authenticator's session rather than the
+// password, and only lives in memory, so keep it server side between requests
+pub struct TwoFactorChallenge {
+    username: String,
+    pending: Box<dyn PendingLogin>,
+}
+
+pub enum AppleIDLogin {
+    Finished(Box<IDSUser>),
+    NeedsTwoFactor(TwoFactorChallenge),
+}
+
+impl TwoFactorChallenge {
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    // where the code went, so the user can be asked for the right kind
+    pub fn second_factor(&self) -> SecondFactor {
+        self.pending.second_factor()
+    }
+
+    // sends a code by SMS; submit it as `TwoFactorCode::Sms`
+    pub async fn request_sms(&mut self) -> Result<(), PushError> {
+        info!("Requesting SMS code for {}", self.username);
+        self.pending.request_sms().await
+    }
+
+    // finishes the login; on TwoFaCodeRejected the challenge can be retried with another code
+    pub async fn submit_code(&self, code: TwoFactorCode) -> Result<IDSUser, PushError> {
+        let (token, user_id) = self.pending.submit_code(&code).await?;
+        IDSAppleUser::finish_login(&user_id, &token).await
+    }
+}
+
 impl IDSAppleUser {
+    // `password` may have the 2FA code appended, see `start_login` for a stepwise flow
     pub async fn authenticate(
         _conn: Arc<APNSConnection>,
         username: &str,
         password: &str,
     ) -> Result<IDSUser, PushError> {
-        let (token, user_id) = get_auth_token(username, password).await?;
-        let auth_keypair = get_auth_cert(&user_id, &token).await?;
+        let (token, user_id) = request_auth_token(username, password).await?;
+        IDSAppleUser::finish_login(&user_id, &token).await
+    }
+
+    // sends the password; if 2FA is on, a code goes out to the user and the returned challenge
+    // finishes the login. only an authenticator that can hold a login open ever returns a
+    // challenge: `ProfileAuthenticator` never does (2FA accounts just fail with TwoFaError), so
+    // the stepwise flow needs `GSAAuthenticator` and an anisette provider the caller supplies
+    pub async fn start_login(
+        authenticator: &dyn AppleIDAuthenticator,
+        username: &str,
+        password: &str,
+    ) -> Result<AppleIDLogin, PushError> {
+        match authenticator.authenticate(username, password).await? {
+            AuthResponse::Token { token, user_id } => Ok(AppleIDLogin::Finished(Box::new(
+                IDSAppleUser::finish_login(&user_id, &token).await?,
+            ))),
+            AuthResponse::NeedsTwoFactor(pending) => {
+                info!(
+                    "2FA required for {}, code sent by {:?}",
+                    username,
+                    pending.second_factor()
+                );
+                Ok(AppleIDLogin::NeedsTwoFactor(TwoFactorChallenge {
+                    username: username.to_string(),
+                    pending,
+                }))
+            }
+        }
+    }
+
+    async fn finish_login(user_id: &str, token: &str) -> Result<IDSUser, PushError> {
+        let auth_keypair = get_auth_cert(user_id, token).await?;
 
         Ok(IDSUser {
             auth_keypair,
-            user_id,
+            user_id: user_id.to_string(),
             handles: vec![],
             identity: None,
             user_type: IDSUserType::Apple,
+            auth: Some(IDSUserAuth::Apple(IDSAppleUser {
+                user_id: user_id.to_string(),
+                auth_token: token.to_string(),
+            })),
         })
     }
 }
 
+#[async_trait]
+impl IDSAuthenticator for IDSAppleUser {
+    async fn reauthenticate(&self, _conn: &APNSConnection) -> Result<KeyPair, PushError> {
+        get_auth_cert(&self.user_id, &self.auth_token).await
+    }
+}
+
 impl IDSPhoneUser {
     pub async fn authenticate(
         conn: Arc<APNSConnection>,
@@ -427,7 +646,7 @@ impl IDSPhoneUser {
     ) -> Result<IDSUser, PushError> {
         let auth_keypair = get_phone_cert(
             phone_number,
-            conn.state.token.as_ref().unwrap(),
+            conn.state().token.as_ref().unwrap(),
             &[phone_sig.to_vec()],
         )
         .await?;
@@ -438,6 +657,23 @@ impl IDSPhoneUser {
             handles: vec![],
             identity: None,
             user_type: IDSUserType::Phone,
+            auth: Some(IDSUserAuth::Phone(IDSPhoneUser {
+                phone_number: phone_number.to_string(),
+                phone_sig: phone_sig.to_vec(),
+            })),
         })
     }
 }
+
+#[async_trait]
+impl IDSAuthenticator for IDSPhoneUser {
+    async fn reauthenticate(&self, conn: &APNSConnection) -> Result<KeyPair, PushError> {
+        let token = conn.state().token.ok_or(PushError::APNSConnectError)?;
+        get_phone_cert(
+            &self.phone_number,
+            &token,
+            std::slice::from_ref(&self.phone_sig),
+        )
+        .await
+    }
+}
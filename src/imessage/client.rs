@@ -2,7 +2,7 @@ use std::{
     collections::HashMap,
     io::Cursor,
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, RwLock},
     time::{Duration, SystemTime, UNIX_EPOCH},
     vec,
 };
@@ -14,16 +14,16 @@ use openssl::{
     hash::MessageDigest,
     pkey::PKey,
     rsa::Padding,
-    sha::sha1,
     sign::Signer,
     symm::{decrypt, encrypt, Cipher},
 };
 use rand::Rng;
-use tokio::sync::{mpsc::Receiver, Mutex};
+use tokio::sync::Mutex;
 use uuid::Uuid;
 
 use crate::{
-    apns::{APNSConnection, APNSPayload},
+    apns::{APNSCommand, APNSConnection, APNSSubscription, OverflowPolicy, SubscriberOptions},
+    config::get_config,
     error::PushError,
     ids::{
         identity::IDSPublicIdentity,
@@ -36,6 +36,11 @@ use crate::{
 use super::messages::{ConversationData, IMessage, Message, RecvMsg};
 
 const PAYLOADS_MAX_SIZE: usize = 10000;
+// deep rather than `Block`, so an app that falls behind can't stall the connection
+const MADRID_OPTIONS: SubscriberOptions = SubscriberOptions {
+    capacity: 1000,
+    overflow: OverflowPolicy::DropOldest,
+};
 const NORMAL_NONCE: [u8; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
 
 // a recieved message, for now just an iMessage
@@ -102,9 +107,10 @@ impl KeyCache {
 
 pub struct IMClient {
     pub conn: Arc<APNSConnection>,
-    pub users: Arc<Vec<IDSUser>>,
+    // swapped out whole when users are reauthenticated
+    users: RwLock<Arc<Vec<IDSUser>>>,
     key_cache: Mutex<KeyCache>,
-    raw_inbound: Mutex<Receiver<APNSPayload>>,
+    raw_inbound: Mutex<APNSSubscription>,
 }
 
 impl IMClient {
@@ -112,35 +118,48 @@ impl IMClient {
         IMClient {
             key_cache: Mutex::new(KeyCache::new()),
             raw_inbound: Mutex::new(
-                conn.reader
-                    .register_for(|pay| {
-                        if pay.id != 0x0A {
-                            return false;
-                        }
-                        if pay.get_field(2).unwrap() != &sha1("com.apple.madrid".as_bytes()) {
-                            return false;
-                        }
-                        let Some(body) = pay.get_field(3) else {
-                            return false;
-                        };
-                        let load = plist::Value::from_reader(Cursor::new(body)).unwrap();
-                        let get_c = load
-                            .as_dictionary()
-                            .unwrap()
-                            .get("c")
-                            .unwrap()
-                            .as_unsigned_integer()
-                            .unwrap();
-                        debug!("mydatsa: {:?}", load);
-                        get_c == 100 || get_c == 101 || get_c == 102 || get_c == 190 || get_c == 118
-                    })
-                    .await,
+                // notifications are acked before they reach us, anything dropped is gone for good
+                conn.subscribe_filtered("com.apple.madrid", MADRID_OPTIONS, |pay| {
+                    let APNSCommand::Notification { payload: body, .. } = pay else {
+                        return false;
+                    };
+                    let load = plist::Value::from_reader(Cursor::new(body)).unwrap();
+                    let get_c = load
+                        .as_dictionary()
+                        .unwrap()
+                        .get("c")
+                        .unwrap()
+                        .as_unsigned_integer()
+                        .unwrap();
+                    debug!("mydatsa: {:?}", load);
+                    get_c == 100 || get_c == 101 || get_c == 102 || get_c == 190 || get_c == 118
+                })
+                .await,
             ),
             conn,
-            users,
+            users: RwLock::new(users),
         }
     }
 
+    pub fn users(&self) -> Arc<Vec<IDSUser>> {
+        self.users.read().unwrap().clone()
+    }
+
+    // e.g. from `RegistrationScheduler::watch_users`, after users were reauthenticated elsewhere
+    pub fn set_users(&self, users: Arc<Vec<IDSUser>>) {
+        *self.users.write().unwrap() = users;
+    }
+
+    // gets every user a new auth cert, save `users()` afterwards
+    pub async fn reauthenticate(&self) -> Result<(), PushError> {
+        let mut users = (*self.users()).clone();
+        for user in users.iter_mut() {
+            user.reauthenticate(self.conn.clone()).await?;
+        }
+        *self.users.write().unwrap() = Arc::new(users);
+        Ok(())
+    }
+
     fn parse_payload(payload: &[u8]) -> (&[u8], &[u8]) {
         let body_len = u16::from_be_bytes(payload[1..3].try_into().unwrap()) as usize;
         let body = &payload[3..(3 + body_len)];
@@ -151,7 +170,7 @@ impl IMClient {
     }
 
     pub fn get_handles(&self) -> Vec<String> {
-        self.users
+        self.users()
             .iter()
             .flat_map(|user| user.handles.clone())
             .collect::<Vec<String>>()
@@ -234,30 +253,29 @@ impl IMClient {
     }
 
     pub async fn recieve(&mut self) -> Option<RecievedMessage> {
-        let Ok(payload) = self.raw_inbound.lock().await.try_recv() else {
-            return None;
-        };
+        let payload = self.raw_inbound.lock().await.try_recv().ok().flatten()?;
         self.recieve_payload(payload).await
     }
 
     pub async fn recieve_wait(&self) -> Option<RecievedMessage> {
-        let Some(payload) = self.raw_inbound.lock().await.recv().await else {
-            return None;
-        };
+        let payload = self.raw_inbound.lock().await.recv().await.ok()?;
         self.recieve_payload(payload).await
     }
 
-    async fn user_by_handle(&self, handle: &str) -> &IDSUser {
-        self.users
+    async fn user_by_handle(&self, handle: &str) -> IDSUser {
+        self.users()
             .iter()
             .find(|user| user.handles.contains(&handle.to_string()))
             .expect(&format!("Cannot find identity for sender {}!", handle))
+            .clone()
     }
 
-    async fn recieve_payload(&self, payload: APNSPayload) -> Option<RecievedMessage> {
-        let body = payload.get_field(3).unwrap();
+    async fn recieve_payload(&self, payload: APNSCommand) -> Option<RecievedMessage> {
+        let APNSCommand::Notification { payload: body, .. } = payload else {
+            return None;
+        };
 
-        let load = plist::Value::from_reader(Cursor::new(body)).unwrap();
+        let load = plist::Value::from_reader(Cursor::new(&body)).unwrap();
         let get_c = load
             .as_dictionary()
             .unwrap()
@@ -336,10 +354,10 @@ impl IMClient {
             return None;
         }
 
-        let loaded: RecvMsg = plist::from_bytes(body).unwrap();
+        let loaded: RecvMsg = plist::from_bytes(&body).unwrap();
 
-        let Some(identity) = self
-            .users
+        let users = self.users();
+        let Some(identity) = users
             .iter()
             .find(|user| user.handles.contains(&loaded.target))
         else {
@@ -503,7 +521,7 @@ impl IMClient {
                 .get_keys(&sender, participant)
                 .ok_or(PushError::KeyNotFound(participant.clone()))?
             {
-                if &token.push_token == self.conn.state.token.as_ref().unwrap() {
+                if &token.push_token == self.conn.state().token.as_ref().unwrap() {
                     // don't send to ourself
                     continue;
                 }
@@ -543,7 +561,7 @@ impl IMClient {
                 } else {
                     None
                 },
-                ua: "[macOS,13.4.1,22F82,MacBookPro18,3]".to_string(),
+                ua: get_config().device.imessage.ua(),
                 v: 8,
                 i: u32::from_be_bytes(msg_id),
                 u: Uuid::from_str(&message.id)
@@ -560,7 +578,7 @@ impl IMClient {
             let binary = plist_to_bin(&complete)?;
             Ok::<(), PushError>(
                 self.conn
-                    .send_message("com.apple.madrid", &binary, Some(&msg_id))
+                    .send_message("com.apple.madrid", &binary, Some(msg_id))
                     .await?,
             )
         };
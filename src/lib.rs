@@ -1,21 +1,44 @@
 mod albert;
 mod apns;
 mod bags;
+mod config;
 mod error;
 mod ids;
 mod imessage;
 mod mmcs;
+mod proxy;
 pub mod util;
 
 pub mod mmcsp {
     include!(concat!(env!("OUT_DIR"), "/mmcsp.rs"));
 }
 
-pub use apns::{APNSConnection, APNSState};
+#[cfg(feature = "test-support")]
+pub use apns::fake_courier::{FakeCourier, SentMessage};
+pub use apns::{
+    APNSCommand, APNSConnection, APNSConnectionState, APNSPayload, APNSReceiver, APNSState,
+    APNSStream, APNSSubscription, APNSTransport, CourierTransport, KeepAlivePolicy,
+    OverflowPolicy, SubscriberOptions,
+};
+pub use albert::{generate_push_cert, parse_activation_response};
+pub use bags::verify_bag;
+pub use config::{
+    get_config, set_config, ActivationDevice, ClientProfile, DeviceProfile, Endpoints,
+    RustpushConfig,
+};
 pub use error::PushError;
+pub use proxy::{get_proxy, set_proxy, ProxyConfig, ProxyKind};
 pub use ids::{
-    identity::register,
-    user::{IDSAppleUser, IDSPhoneUser, IDSUser},
+    gsa::{AnisetteProvider, GSAAuthenticator, SrpClient},
+    identity::{refresh_users, register},
+    scheduler::{
+        RegistrationEvent, RegistrationPolicy, RegistrationScheduler, ValidationDataProvider,
+    },
+    user::{
+        AppleIDAuthenticator, AppleIDLogin, AuthResponse, IDSAppleUser, IDSAuthenticator,
+        IDSPhoneUser, IDSUser, IDSUserAuth, PendingLogin, ProfileAuthenticator, SecondFactor,
+        TwoFactorChallenge, TwoFactorCode,
+    },
 };
 pub use imessage::client::{IMClient, RecievedMessage};
 pub use imessage::messages::{
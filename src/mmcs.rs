@@ -1,9 +1,10 @@
 use std::{collections::HashMap, io::Cursor};
 
 use crate::{
+    config::{get_config, http_client},
     error::PushError,
     mmcsp::{self, authorize_put_response::UploadTarget, Container as ProtoContainer, HttpRequest},
-    util::{make_reqwest, plist_to_bin},
+    util::plist_to_bin,
     APNSConnection,
 };
 use async_trait::async_trait;
@@ -44,10 +45,10 @@ async fn send_mmcs_req(
         .header("Accept", "application/vnd.com.apple.me.ubchunk+protobuf")
         .header("x-apple-request-uuid", Uuid::new_v4().to_string().to_uppercase())
         .header("x-apple-mme-dsid", dsid)
-        .header("x-mme-client-info", "<iMac13,1> <macOS;12.6.9;21G726> <com.apple.icloud.content/1950.19 (com.apple.Messenger/1.0)>")
+        .header("x-mme-client-info", get_config().device.mmcs.mme_client_info())
         .header("Accept-Language", "en-us")
         .header("Content-Type", "application/vnd.com.apple.me.ubchunk+protobuf")
-        .header("User-Agent", "IMTransferAgent/1000 CFNetwork/1335.0.3.4 Darwin/21.6.0")
+        .header("User-Agent", &get_config().mmcs_user_agent)
         .header("x-apple-mmcs-proto-version", "5.0")
         .header("x-apple-mmcs-plist-version", "v1.0")
         .header("Accept-Encoding", "gzip, deflate")
@@ -178,7 +179,7 @@ impl MMCSPutContainer {
             let request = self.target.request.clone().unwrap();
             let task = tokio::spawn(async move {
                 let response =
-                    transfer_mmcs_container(&make_reqwest(), &request, Some(body)).await?;
+                    transfer_mmcs_container(&http_client(), &request, Some(body)).await?;
                 response.bytes().await?;
                 Ok::<(), PushError>(())
             });
@@ -300,9 +301,10 @@ pub async fn put_mmcs(
     get.encode(&mut buf).unwrap();
 
     let msg_id = rand::thread_rng().gen::<[u8; 4]>();
+    let device = &get_config().device.mmcs;
     let complete = RequestMMCSUpload {
         c: 150,
-        ua: "[macOS,12.6.9,21G726,iMac13,1]".to_string(),
+        ua: device.ua(),
         v: 3,
         i: u32::from_be_bytes(msg_id),
         length: prepared.total_len,
@@ -312,18 +314,14 @@ pub async fn put_mmcs(
             "x-apple-mmcs-proto-version:5.0",
             "x-apple-mmcs-plist-sha256:fvj0Y/Ybu1pq0r4NxXw3eP51exujUkEAd7LllbkTdK8=",
             "x-apple-mmcs-plist-version:v1.0",
-            "x-mme-client-info:<iMac13,1> <macOS;12.6.9;21G726> <com.apple.icloud.content/1950.19 (com.apple.Messenger/1.0)>",
+            &format!("x-mme-client-info:{}", device.mme_client_info()),
             ""
         ].join("\n"),
         body: buf.into()
     };
     let binary = plist_to_bin(&complete)?;
-    apns.send_message("com.apple.madrid", &binary, Some(&msg_id))
-        .await?;
-
     let response = apns
-        .reader
-        .wait_find_msg(move |loaded| {
+        .send_message_for_reply("com.apple.madrid", &binary, Some(msg_id), move |loaded| {
             let Some(c) = loaded.as_dictionary().unwrap().get("c") else {
                 return false;
             };
@@ -333,9 +331,8 @@ pub async fn put_mmcs(
             c.as_unsigned_integer().unwrap() == 150
                 && i.as_unsigned_integer().unwrap() as u32 == u32::from_be_bytes(msg_id)
         })
-        .await;
-    let apns_response: MMCSUploadResponse =
-        plist::from_bytes(response.get_field(3).unwrap()).unwrap();
+        .await?;
+    let apns_response: MMCSUploadResponse = plist::from_value(&response).unwrap();
 
     let response =
         mmcsp::AuthorizePutResponse::decode(&mut Cursor::new(apns_response.response)).unwrap();
@@ -396,10 +393,7 @@ pub async fn transfer_mmcs_container(
         "x-apple-request-uuid",
         Uuid::new_v4().to_string().to_uppercase(),
     )
-    .header(
-        "user-agent",
-        "IMTransferAgent/1000 CFNetwork/1335.0.3.4 Darwin/21.6.0",
-    );
+    .header("user-agent", &get_config().mmcs_user_agent);
     for header in &req.headers {
         if header.name == "Content-Length" || header.name == "Host" {
             continue; // this isn't a rustpush hack, this is how you *think different*
@@ -634,7 +628,7 @@ impl MMCSGetContainer {
     async fn ensure_stream(&mut self) {
         if self.response.is_none() {
             let response = transfer_mmcs_container(
-                &make_reqwest(),
+                &http_client(),
                 &self.container.request.as_ref().unwrap(),
                 None,
             )
@@ -718,15 +712,16 @@ pub async fn get_mmcs(
 ) -> Result<(), PushError> {
     let domain = url.replace(&format!("/{}", object), "");
     let msg_id = rand::thread_rng().gen::<[u8; 4]>();
+    let device = &get_config().device.mmcs;
     let request_download = RequestMMCSDownload {
         object: object.to_string(),
         c: 151,
-        ua: "[macOS,12.6.9,21G726,iMac13,1]".to_string(),
+        ua: device.ua(),
         headers: [
             "x-apple-mmcs-proto-version:5.0",
             "x-apple-mmcs-plist-sha256:fvj0Y/Ybu1pq0r4NxXw3eP51exujUkEAd7LllbkTdK8=",
             "x-apple-mmcs-plist-version:v1.0",
-            "x-mme-client-info:<iMac13,1> <macOS;12.6.9;21G726> <com.apple.icloud.content/1950.19 (com.apple.Messenger/1.0)>",
+            &format!("x-mme-client-info:{}", device.mme_client_info()),
             ""
         ].join("\n"),
         v: 8,
@@ -737,12 +732,8 @@ pub async fn get_mmcs(
     };
 
     let binary = plist_to_bin(&request_download)?;
-    apns.send_message("com.apple.madrid", &binary, Some(&msg_id))
-        .await?;
-
     let response = apns
-        .reader
-        .wait_find_msg(move |loaded| {
+        .send_message_for_reply("com.apple.madrid", &binary, Some(msg_id), move |loaded| {
             let Some(c) = loaded.as_dictionary().unwrap().get("c") else {
                 return false;
             };
@@ -752,9 +743,8 @@ pub async fn get_mmcs(
             c.as_unsigned_integer().unwrap() == 151
                 && i.as_unsigned_integer().unwrap() as u32 == u32::from_be_bytes(msg_id)
         })
-        .await;
-    let apns_response: MMCSDownloadResponse =
-        plist::from_bytes(response.get_field(3).unwrap()).unwrap();
+        .await?;
+    let apns_response: MMCSDownloadResponse = plist::from_value(&response).unwrap();
 
     let data: Vec<u8> = apns_response.response.clone().into();
     let response = mmcsp::AuthorizeGetResponse::decode(&mut Cursor::new(data)).unwrap();
@@ -824,7 +814,7 @@ pub async fn get_mmcs(
     buf.reserve(confirmation.encoded_len());
     confirmation.encode(&mut buf).unwrap();
     let resp = send_mmcs_req(
-        &make_reqwest(),
+        &http_client(),
         url,
         "getComplete",
         &format!("{} {}", data[0].cl_auth_p1, data[0].cl_auth_p2),
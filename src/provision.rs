@@ -0,0 +1,99 @@
+// generates push credentials ahead of time, so the messaging service can start from them
+// usage: rustpush-provision [--force] [OUTPUT], OUTPUT defaults to push.json
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::exit,
+    time::SystemTime,
+};
+
+use log::warn;
+use rustpush::{generate_push_cert, init_logger, APNSConnection, APNSState, PushError};
+use tokio::{fs, io::AsyncWriteExt};
+
+async fn provision() -> Result<APNSState, PushError> {
+    println!("Generating push certificate...");
+    let keypair = generate_push_cert().await?;
+
+    println!("Connecting to APNs for a push token...");
+    let connection = APNSConnection::new(Some(APNSState {
+        keypair,
+        token: None,
+    }))
+    .await?;
+    let state = connection.state();
+    // we already have the token, so a messy goodbye shouldn't throw it away
+    if let Err(err) = connection.shutdown().await {
+        warn!("failed to close the APNs connection cleanly: {:?}", err);
+    }
+    Ok(state)
+}
+
+// the file holds the push private key, so only the owner may read it
+async fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        options.mode(0o600);
+        // `mode` only applies to new files, and --force may be replacing an old one
+        if fs::try_exists(path).await? {
+            fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+        }
+    }
+    let mut file = options.open(path).await?;
+    file.write_all(data).await?;
+    file.flush().await
+}
+
+#[tokio::main]
+async fn main() {
+    init_logger();
+
+    let mut force = false;
+    let mut output = PathBuf::from("push.json");
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--force" => force = true,
+            "-h" | "--help" => {
+                println!("usage: rustpush-provision [--force] [OUTPUT]");
+                return;
+            }
+            _ => output = PathBuf::from(arg),
+        }
+    }
+
+    if !force && fs::try_exists(&output).await.unwrap_or(false) {
+        eprintln!("{:?} already exists, pass --force to replace it", output);
+        exit(1);
+    }
+
+    let state = match provision().await {
+        Ok(state) => state,
+        Err(err) => {
+            eprintln!("Failed to provision: {:?}", err);
+            exit(1);
+        }
+    };
+
+    let serialized = serde_json::to_string(&state).unwrap();
+    if let Err(err) = write_private(&output, serialized.as_bytes()).await {
+        eprintln!("Unable to write {:?}: {:?}", output, err);
+        exit(1);
+    }
+
+    match state.cert_expiry() {
+        Ok(expiry) => {
+            let days = expiry
+                .duration_since(SystemTime::now())
+                .map_or(0, |left| left.as_secs() / (24 * 60 * 60));
+            println!(
+                "Wrote {:?}, push certificate valid for {} days",
+                output, days
+            )
+        }
+        Err(_) => println!("Wrote {:?}", output),
+    }
+}
@@ -0,0 +1,131 @@
+use log::debug;
+use reqwest::Proxy;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_socks::tcp::Socks5Stream;
+
+use crate::{
+    config::{get_config, set_config},
+    util::base64_encode,
+    PushError,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProxyKind {
+    // HTTP CONNECT
+    Http,
+    // hostnames are resolved by the proxy
+    Socks5,
+}
+
+// routes every connection we make (albert, bags, IDS, MMCS and the courier) through a proxy
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyConfig {
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+// shorthand for changing just `RustpushConfig::proxy`
+pub fn set_proxy(proxy: Option<ProxyConfig>) -> Result<(), PushError> {
+    let mut config = (*get_config()).clone();
+    config.proxy = proxy;
+    set_config(config)
+}
+
+pub fn get_proxy() -> Option<ProxyConfig> {
+    get_config().proxy.clone()
+}
+
+impl ProxyConfig {
+    pub fn http(host: &str, port: u16) -> ProxyConfig {
+        ProxyConfig {
+            kind: ProxyKind::Http,
+            host: host.to_string(),
+            port,
+            username: None,
+            password: None,
+        }
+    }
+
+    pub fn socks5(host: &str, port: u16) -> ProxyConfig {
+        ProxyConfig {
+            kind: ProxyKind::Socks5,
+            host: host.to_string(),
+            port,
+            username: None,
+            password: None,
+        }
+    }
+
+    pub fn with_auth(mut self, username: &str, password: &str) -> ProxyConfig {
+        self.username = Some(username.to_string());
+        self.password = Some(password.to_string());
+        self
+    }
+
+    pub(crate) fn to_reqwest(&self) -> Result<Proxy, PushError> {
+        let scheme = match self.kind {
+            ProxyKind::Http => "http",
+            ProxyKind::Socks5 => "socks5h",
+        };
+        let mut proxy = Proxy::all(format!("{}://{}:{}", scheme, self.host, self.port))?;
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            proxy = proxy.basic_auth(username, password);
+        }
+        Ok(proxy)
+    }
+
+    // a raw TCP stream to host:port through the proxy
+    pub(crate) async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, PushError> {
+        debug!("connecting to {}:{} via {:?} proxy", host, port, self.kind);
+        let proxy = (self.host.as_str(), self.port);
+        match self.kind {
+            ProxyKind::Socks5 => {
+                let stream = match (&self.username, &self.password) {
+                    (Some(username), Some(password)) => {
+                        Socks5Stream::connect_with_password(proxy, (host, port), username, password)
+                            .await
+                    }
+                    _ => Socks5Stream::connect(proxy, (host, port)).await,
+                }
+                .map_err(|err| PushError::ProxyError(err.to_string()))?;
+                Ok(stream.into_inner())
+            }
+            ProxyKind::Http => {
+                let mut stream = TcpStream::connect(proxy).await?;
+                let mut request = format!(
+                    "CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n",
+                    host = host,
+                    port = port
+                );
+                if let (Some(username), Some(password)) = (&self.username, &self.password) {
+                    let credentials =
+                        base64_encode(format!("{}:{}", username, password).as_bytes());
+                    request += &format!("Proxy-Authorization: Basic {}\r\n", credentials);
+                }
+                request += "\r\n";
+                stream.write_all(request.as_bytes()).await?;
+
+                // read byte by byte so nothing after the headers is consumed
+                let mut response = vec![];
+                while !response.ends_with(b"\r\n\r\n") {
+                    if response.len() > 8192 {
+                        return Err(PushError::ProxyError("response too long".to_string()));
+                    }
+                    response.push(stream.read_u8().await?);
+                }
+                let response = String::from_utf8_lossy(&response);
+                let status = response.lines().next().unwrap_or_default();
+                if status.split_whitespace().nth(1) != Some("200") {
+                    return Err(PushError::ProxyError(format!("CONNECT failed: {}", status)));
+                }
+                Ok(stream)
+            }
+        }
+    }
+}
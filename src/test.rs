@@ -105,12 +105,25 @@ async fn main() {
     }
 
     let state = SavedState {
-        push: connection.state.clone(),
+        push: connection.state(),
         users: users.clone(),
     };
     let serialized = serde_json::to_string(&state).unwrap();
     fs::write("config.json", serialized).await.unwrap();
 
+    // keep config.json current if a reconnect hands us a new token
+    let mut push_state = connection.watch_state();
+    tokio::spawn(async move {
+        while push_state.changed().await.is_ok() {
+            let state = SavedState {
+                push: push_state.borrow().clone(),
+                users: state.users.clone(),
+            };
+            let serialized = serde_json::to_string(&state).unwrap();
+            fs::write("config.json", serialized).await.unwrap();
+        }
+    });
+
     let users = Arc::new(users);
     let mut client = IMClient::new(connection.clone(), users.clone()).await;
     let handle = client.get_handles()[0].clone();
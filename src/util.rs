@@ -4,44 +4,15 @@ use std::num::ParseIntError;
 use base64::engine::general_purpose;
 use base64::Engine;
 use libflate::gzip::{Decoder, EncodeOptions, Encoder, HeaderBuilder};
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::x509::X509;
 use plist::{Error, Value};
-use reqwest::{Certificate, Client};
 use serde::{Deserialize, Serialize};
 use std::fmt::Write as FmtWrite;
 use std::io::{Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub fn make_reqwest() -> Client {
-    let certificates = vec![
-        Certificate::from_pem(include_bytes!(
-            "../certs/root/albert.apple.com.digicert.cert"
-        ))
-        .unwrap(),
-        Certificate::from_pem(include_bytes!(
-            "../certs/root/profileidentity.ess.apple.com.cert"
-        ))
-        .unwrap(),
-        Certificate::from_pem(include_bytes!(
-            "../certs/root/init-p01st.push.apple.com.cert"
-        ))
-        .unwrap(),
-        Certificate::from_pem(include_bytes!("../certs/root/init.ess.apple.com.cert")).unwrap(),
-        Certificate::from_pem(include_bytes!("../certs/root/content-icloud-com.cert")).unwrap(),
-    ];
-    let mut builder = reqwest::Client::builder()
-        .use_rustls_tls()
-        .tls_built_in_root_certs(false);
-
-    for certificate in certificates.into_iter() {
-        builder = builder.add_root_certificate(certificate);
-    }
-
-    /*let builder = reqwest::Client::builder()
-    .use_rustls_tls()
-    .proxy(Proxy::https("https://localhost:8080").unwrap())
-    .danger_accept_invalid_certs(true);*/
-
-    builder.build().unwrap()
-}
+use crate::PushError;
 
 pub fn get_nested_value<'s>(val: &'s Value, path: &[&str]) -> Option<&'s Value> {
     let mut curr_val = val;
@@ -58,6 +29,21 @@ pub struct KeyPair {
     pub private: Vec<u8>,
 }
 
+fn asn1_to_system_time(time: &Asn1TimeRef) -> Result<SystemTime, PushError> {
+    let diff = Asn1Time::from_unix(0)?.diff(time)?;
+    let secs = diff.days as i64 * 24 * 60 * 60 + diff.secs as i64;
+    Ok(UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64))
+}
+
+// (not before, not after) of a DER certificate
+pub fn cert_validity(der: &[u8]) -> Result<(SystemTime, SystemTime), PushError> {
+    let cert = X509::from_der(der)?;
+    Ok((
+        asn1_to_system_time(cert.not_before())?,
+        asn1_to_system_time(cert.not_after())?,
+    ))
+}
+
 pub fn base64_encode(data: &[u8]) -> String {
     general_purpose::STANDARD.encode(data)
 }
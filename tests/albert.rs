@@ -0,0 +1,96 @@
+use openssl::x509::X509;
+use reqwest::StatusCode;
+use rustpush::{parse_activation_response, PushError};
+
+fn parse(fixture: &str) -> Result<Vec<u8>, PushError> {
+    let path = format!(
+        "{}/tests/fixtures/albert/{}",
+        env!("CARGO_MANIFEST_DIR"),
+        fixture
+    );
+    let body = std::fs::read_to_string(path).unwrap();
+    parse_activation_response(StatusCode::OK, &body)
+}
+
+#[test]
+fn parses_device_certificate() {
+    let cert = parse("success.html").unwrap();
+    let cert = X509::from_der(&cert).unwrap();
+    let subject = cert.subject_name().entries().last().unwrap();
+    assert_eq!(
+        subject.data().as_utf8().unwrap().to_string(),
+        "Apple iPhone Device CA Test"
+    );
+}
+
+#[test]
+fn parses_multiline_protocol() {
+    assert_eq!(
+        parse("success_multiline.html").unwrap(),
+        parse("success.html").unwrap()
+    );
+}
+
+#[test]
+fn http_error() {
+    let body = std::fs::read_to_string(format!(
+        "{}/tests/fixtures/albert/success.html",
+        env!("CARGO_MANIFEST_DIR")
+    ))
+    .unwrap();
+    assert!(matches!(
+        parse_activation_response(StatusCode::SERVICE_UNAVAILABLE, &body),
+        Err(PushError::AlbertStatusError(
+            StatusCode::SERVICE_UNAVAILABLE
+        ))
+    ));
+}
+
+#[test]
+fn refused() {
+    assert!(matches!(
+        parse("refused.html"),
+        Err(PushError::AlbertActivationRefused(message)) if message == "no activation record"
+    ));
+}
+
+#[test]
+fn refused_with_message() {
+    assert!(matches!(
+        parse("refused_with_message.html"),
+        Err(PushError::AlbertActivationRefused(message))
+            if message == "This device could not be activated."
+    ));
+}
+
+#[test]
+fn missing_certificate() {
+    assert!(matches!(
+        parse("missing_certificate.html"),
+        Err(PushError::AlbertMissingCertificate)
+    ));
+}
+
+#[test]
+fn malformed_certificate() {
+    assert!(matches!(
+        parse("malformed_certificate.html"),
+        Err(PushError::AlbertMalformedCertificate(_))
+    ));
+}
+
+#[test]
+fn no_protocol() {
+    assert!(matches!(
+        parse("no_protocol.html"),
+        Err(PushError::AlbertCertParseError)
+    ));
+}
+
+#[test]
+fn malformed_protocol() {
+    assert!(matches!(
+        parse("malformed_protocol.html"),
+        Err(PushError::AlbertCertParseError)
+    ));
+}
@@ -0,0 +1,162 @@
+use rustpush::{APNSCommand, APNSPayload, PushError};
+
+// matches MAX_FRAME_LEN in src/apns/command.rs
+const MAX_FRAME_LEN: usize = 1 << 24;
+
+fn is_malformed<T: std::fmt::Debug>(result: Result<T, PushError>) -> bool {
+    matches!(result, Err(PushError::MalformedAPNSPayload(_)))
+}
+
+fn round_trip_outgoing(command: APNSCommand) {
+    let frame = command.serialize().unwrap();
+    let payload = APNSPayload::decode(&frame).unwrap();
+    assert_eq!(APNSCommand::decode_outgoing(&payload).unwrap(), command);
+}
+
+fn round_trip_incoming(command: APNSCommand) {
+    let frame = command.serialize().unwrap();
+    let payload = APNSPayload::decode(&frame).unwrap();
+    assert_eq!(APNSCommand::decode_incoming(&payload).unwrap(), command);
+}
+
+#[test]
+fn round_trips_client_commands() {
+    for token in [None, Some(vec![7; 32])] {
+        round_trip_outgoing(APNSCommand::Connect {
+            token,
+            state: 1,
+            flags: 0b01000001,
+            cert: vec![1; 900],
+            nonce: vec![2; 17],
+            signature: vec![3; 258],
+        });
+    }
+    round_trip_outgoing(APNSCommand::Filter {
+        token: vec![7; 32],
+        topics: vec![
+            APNSCommand::topic_hash("com.apple.madrid"),
+            APNSCommand::topic_hash("com.apple.private.alloy.sms"),
+        ],
+    });
+    round_trip_outgoing(APNSCommand::SendMessage {
+        id: [1, 2, 3, 4],
+        topic: APNSCommand::topic_hash("com.apple.madrid"),
+        token: vec![7; 32],
+        payload: vec![9; u16::MAX as usize],
+    });
+    round_trip_outgoing(APNSCommand::KeepAlive);
+    round_trip_outgoing(APNSCommand::SetState { state: 1 });
+}
+
+#[test]
+fn round_trips_courier_commands() {
+    for token in [None, Some(vec![7; 32])] {
+        round_trip_incoming(APNSCommand::ConnectResponse { status: 0, token });
+    }
+    round_trip_incoming(APNSCommand::Notification {
+        id: [1, 2, 3, 4],
+        topic: APNSCommand::topic_hash("com.apple.madrid"),
+        token: vec![7; 32],
+        payload: b"bplist00".to_vec(),
+    });
+    round_trip_incoming(APNSCommand::Ack {
+        token: Some(vec![7; 32]),
+        id: [1, 2, 3, 4],
+        status: 0,
+    });
+    round_trip_incoming(APNSCommand::KeepAliveAck);
+}
+
+#[test]
+fn round_trips_unknown_commands() {
+    let payload = APNSPayload::new(0x42, vec![(1, vec![1, 2]), (1, vec![]), (9, vec![3])]);
+    let frame = payload.serialize().unwrap();
+    let decoded = APNSPayload::decode(&frame).unwrap();
+    assert_eq!(decoded, payload);
+    assert_eq!(
+        APNSCommand::decode_incoming(&decoded).unwrap(),
+        APNSCommand::Unknown(payload)
+    );
+}
+
+#[test]
+fn encodes_frame_layout() {
+    assert_eq!(
+        APNSCommand::SetState { state: 1 }.serialize().unwrap(),
+        [0x14, 0, 0, 0, 11, 1, 0, 1, 1, 2, 0, 4, 0x7F, 0xFF, 0xFF, 0xFF]
+    );
+    assert_eq!(
+        APNSCommand::KeepAlive.serialize().unwrap(),
+        [0x0C, 0, 0, 0, 0]
+    );
+}
+
+#[test]
+fn truncated_frame_header() {
+    assert!(is_malformed(APNSPayload::decode(&[])));
+    assert!(is_malformed(APNSPayload::decode(&[0x0A, 0, 0])));
+}
+
+#[test]
+fn truncated_field_header() {
+    // a field id and half a length
+    assert!(is_malformed(APNSPayload::decode(&[0x0A, 0, 0, 0, 2, 1, 0])));
+}
+
+#[test]
+fn overlong_field_length() {
+    // field 1 claims 10 bytes, only 2 follow
+    assert!(is_malformed(APNSPayload::decode(&[
+        0x0A, 0, 0, 0, 5, 1, 0, 10, 0xAA, 0xBB
+    ])));
+}
+
+#[test]
+fn frame_length_mismatch() {
+    let mut frame = APNSCommand::KeepAlive.serialize().unwrap();
+    frame.push(0);
+    assert!(is_malformed(APNSPayload::decode(&frame)));
+    assert!(is_malformed(APNSPayload::decode(&[0x0C, 0, 0, 0, 1])));
+}
+
+#[test]
+fn wrong_size_fixed_fields() {
+    // 3 byte message id
+    let ack = APNSPayload::new(0x0B, vec![(4, vec![1, 2, 3]), (8, vec![0])]);
+    assert!(is_malformed(APNSCommand::decode_incoming(&ack)));
+    // 2 byte status
+    let response = APNSPayload::new(0x08, vec![(1, vec![0, 0])]);
+    assert!(is_malformed(APNSCommand::decode_incoming(&response)));
+    // topic that isn't a sha1 hash
+    let filter = APNSPayload::new(0x09, vec![(1, vec![7; 32]), (2, vec![1; 19])]);
+    assert!(is_malformed(APNSCommand::decode_outgoing(&filter)));
+}
+
+#[test]
+fn missing_required_field() {
+    let notification = APNSPayload::new(0x0A, vec![(1, vec![7; 32]), (4, vec![1, 2, 3, 4])]);
+    assert!(is_malformed(APNSCommand::decode_incoming(&notification)));
+}
+
+#[test]
+fn frame_over_limit() {
+    let len = (MAX_FRAME_LEN as u32 + 1).to_be_bytes();
+    let header = [0x0A, len[0], len[1], len[2], len[3]];
+    assert!(is_malformed(APNSPayload::decode(&header)));
+
+    // every field fits, but together they don't
+    let field = vec![0; u16::MAX as usize];
+    let fields = vec![(3, field); MAX_FRAME_LEN / u16::MAX as usize + 1];
+    assert!(is_malformed(APNSPayload::new(0x0A, fields).serialize()));
+}
+
+#[test]
+fn field_too_long_to_encode() {
+    let command = APNSCommand::SendMessage {
+        id: [1, 2, 3, 4],
+        topic: APNSCommand::topic_hash("com.apple.madrid"),
+        token: vec![7; 32],
+        payload: vec![9; u16::MAX as usize + 1],
+    };
+    assert!(is_malformed(command.serialize()));
+}
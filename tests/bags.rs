@@ -0,0 +1,209 @@
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    sign::Signer,
+    x509::{
+        extension::{BasicConstraints, KeyUsage},
+        X509Builder, X509Name, X509NameBuilder, X509,
+    },
+};
+use plist::{Dictionary, Value};
+use rustpush::{verify_bag, PushError, RustpushConfig};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+struct Issued {
+    cert: X509,
+    key: PKey<Private>,
+}
+
+fn name(organization: &str, common_name: &str) -> X509Name {
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::ORGANIZATIONNAME, organization)
+        .unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name)
+        .unwrap();
+    name.build()
+}
+
+// signed by `issuer`, or self-signed without one
+fn issue(subject: X509Name, issuer: Option<&Issued>, ca: bool) -> Issued {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    let serial = BigNum::from_u32(next_serial()).unwrap();
+    builder
+        .set_serial_number(&serial.to_asn1_integer().unwrap())
+        .unwrap();
+    builder.set_subject_name(&subject).unwrap();
+    match issuer {
+        Some(issuer) => builder.set_issuer_name(issuer.cert.subject_name()).unwrap(),
+        None => builder.set_issuer_name(&subject).unwrap(),
+    }
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(30).unwrap())
+        .unwrap();
+    if ca {
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+        builder
+            .append_extension(KeyUsage::new().critical().key_cert_sign().build().unwrap())
+            .unwrap();
+    }
+    let signing_key = issuer.map_or(&key, |issuer| &issuer.key);
+    builder.sign(signing_key, MessageDigest::sha256()).unwrap();
+    Issued {
+        cert: builder.build(),
+        key,
+    }
+}
+
+fn next_serial() -> u32 {
+    static NEXT: AtomicU32 = AtomicU32::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+fn bag_bytes() -> Vec<u8> {
+    let bag = Value::Dictionary(Dictionary::from_iter([
+        (
+            "APNSCourierHostname",
+            Value::String("courier.push.apple.com".to_string()),
+        ),
+        ("APNSCourierHostcount", Value::Integer(50.into())),
+    ]));
+    let mut data = vec![];
+    bag.to_writer_xml(&mut data).unwrap();
+    data
+}
+
+fn response(bag: &[u8], signed_bag: &[u8], signer: &Issued, chain: &[&Issued]) -> Vec<u8> {
+    let mut sig = Signer::new(MessageDigest::sha1(), &signer.key).unwrap();
+    sig.update(signed_bag).unwrap();
+    let signature = sig.sign_to_vec().unwrap();
+    let certs = [signer]
+        .iter()
+        .chain(chain.iter())
+        .map(|issued| Value::Data(issued.cert.to_der().unwrap()))
+        .collect();
+    let response = Value::Dictionary(Dictionary::from_iter([
+        ("bag", Value::Data(bag.to_vec())),
+        ("certs", Value::Array(certs)),
+        ("signature", Value::Data(signature)),
+    ]));
+    let mut data = vec![];
+    response.to_writer_xml(&mut data).unwrap();
+    data
+}
+
+fn pem(issued: &Issued) -> Vec<u8> {
+    issued.cert.to_pem().unwrap()
+}
+
+fn is_signature_error(result: Result<Dictionary, PushError>) -> bool {
+    matches!(result, Err(PushError::BagSignatureError(_)))
+}
+
+#[test]
+fn accepts_apple_signed_bag() {
+    let root = issue(name("Apple Inc.", "Test Apple Root"), None, true);
+    let ca = issue(
+        name("Apple Inc.", "Test Apple Server CA"),
+        Some(&root),
+        true,
+    );
+    let leaf = issue(
+        name("Apple Inc.", "init-p01st.push.apple.com"),
+        Some(&ca),
+        false,
+    );
+    let bag = bag_bytes();
+    let bag = verify_bag(&response(&bag, &bag, &leaf, &[&ca]), &[pem(&ca)]).unwrap();
+    assert_eq!(
+        bag.get("APNSCourierHostname").unwrap().as_string(),
+        Some("courier.push.apple.com")
+    );
+}
+
+#[test]
+fn rejects_non_apple_signer() {
+    // a cert the pinned CA issued for someone else's domain
+    let ca = issue(name("Apple Inc.", "Test Apple Server CA"), None, true);
+    let leaf = issue(name("Evil Corp", "evil.example"), Some(&ca), false);
+    let bag = bag_bytes();
+    assert!(is_signature_error(verify_bag(
+        &response(&bag, &bag, &leaf, &[]),
+        &[pem(&ca)]
+    )));
+}
+
+#[test]
+fn rejects_unpinned_ca() {
+    let pinned = issue(name("Apple Inc.", "Test Apple Server CA"), None, true);
+    let other = issue(name("Public CA", "Some Web CA"), None, true);
+    let leaf = issue(
+        name("Apple Inc.", "init-p01st.push.apple.com"),
+        Some(&other),
+        false,
+    );
+    let bag = bag_bytes();
+    assert!(is_signature_error(verify_bag(
+        &response(&bag, &bag, &leaf, &[&other]),
+        &[pem(&pinned)]
+    )));
+}
+
+#[test]
+fn rejects_tampered_bag() {
+    let ca = issue(name("Apple Inc.", "Test Apple Server CA"), None, true);
+    let leaf = issue(
+        name("Apple Inc.", "init-p01st.push.apple.com"),
+        Some(&ca),
+        false,
+    );
+    let bag = bag_bytes();
+    let tampered = String::from_utf8(bag.clone())
+        .unwrap()
+        .replace("courier.push.apple.com", "courier.evil.example")
+        .into_bytes();
+    assert!(is_signature_error(verify_bag(
+        &response(&tampered, &bag, &leaf, &[]),
+        &[pem(&ca)]
+    )));
+}
+
+#[test]
+fn rejects_unsigned_bag() {
+    let ca = issue(name("Apple Inc.", "Test Apple Server CA"), None, true);
+    let response = Value::Dictionary(Dictionary::from_iter([("bag", Value::Data(bag_bytes()))]));
+    let mut data = vec![];
+    response.to_writer_xml(&mut data).unwrap();
+    assert!(is_signature_error(verify_bag(&data, &[pem(&ca)])));
+}
+
+#[test]
+fn verifies_by_default_against_apple_roots() {
+    let config = RustpushConfig::default();
+    assert!(config.verify_bags);
+    assert!(!config.bag_signing_roots.is_empty());
+    for root in &config.bag_signing_roots {
+        let cert = X509::from_pem(root).unwrap();
+        let organization = cert
+            .subject_name()
+            .entries_by_nid(Nid::ORGANIZATIONNAME)
+            .next()
+            .unwrap()
+            .data()
+            .as_utf8()
+            .unwrap()
+            .to_string();
+        assert_eq!(organization, "Apple Inc.");
+    }
+}
@@ -0,0 +1,38 @@
+use rustpush::{ClientProfile, DeviceProfile};
+
+// what rustpush sent before the profile was configurable
+#[test]
+fn defaults_match_legacy_identities() {
+    let device = DeviceProfile::default();
+    let activation = &device.activation;
+    assert_eq!(activation.device_class, "Windows");
+    assert_eq!(activation.product_type, "windows1,1");
+    assert_eq!(activation.product_version, "10.6.4");
+    assert_eq!(activation.build_version, "10.6.4");
+    assert_eq!(activation.serial_number, "WindowSerial");
+    assert_eq!(activation.unique_device_id, None);
+
+    assert_eq!(
+        device.ids,
+        ClientProfile::mac("MacBookPro18,3", "13.2.1", "22D68")
+    );
+    assert_eq!(
+        device.imessage,
+        ClientProfile::mac("MacBookPro18,3", "13.4.1", "22F82")
+    );
+    assert_eq!(
+        device.mmcs,
+        ClientProfile::mac("iMac13,1", "12.6.9", "21G726")
+    );
+}
+
+#[test]
+fn default_activation_endpoint_leaves_device_to_profile() {
+    let config = rustpush::RustpushConfig::default();
+    // `device=` comes from the activation profile when the request is made
+    assert!(config
+        .endpoints
+        .albert_activation
+        .ends_with("/deviceActivation"));
+    assert_eq!(config.device.activation.device_class, "Windows");
+}
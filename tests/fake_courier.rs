@@ -0,0 +1,331 @@
+use std::{sync::Arc, time::Duration};
+
+use openssl::{pkey::PKey, rsa::Rsa};
+use rustpush::{
+    util::KeyPair, APNSCommand, APNSConnection, APNSConnectionState, ConversationData, FakeCourier,
+    IMClient, KeepAlivePolicy, Message, NormalMessage, OverflowPolicy, PushError, RecievedMessage,
+    SubscriberOptions,
+};
+use tokio::time::timeout;
+use uuid::Uuid;
+
+const WAIT: Duration = Duration::from_secs(10);
+
+async fn send_text(client: &IMClient, from: &str, to: &str, text: &str) {
+    let mut msg = client
+        .new_msg(
+            ConversationData {
+                participants: vec![to.to_string(), from.to_string()],
+                cv_name: None,
+                sender_guid: Some(Uuid::new_v4().to_string()),
+            },
+            from,
+            Message::Message(NormalMessage::new(text.to_string())),
+        )
+        .await;
+    client.send(&mut msg).await.unwrap();
+}
+
+// as displayed, "[sender] 'text'"
+async fn recv_text(client: &IMClient) -> String {
+    let received = timeout(WAIT, client.recieve_wait()).await.unwrap();
+    let Some(RecievedMessage::Message { msg }) = received else {
+        panic!("nothing received")
+    };
+    msg.to_string()
+}
+
+async fn wait_connected(conn: &APNSConnection) {
+    let mut state = conn.watch_connection_state();
+    timeout(
+        WAIT,
+        state.wait_for(|state| matches!(state, APNSConnectionState::Connected { .. })),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+}
+
+async fn wait_disconnected(conn: &APNSConnection) {
+    let mut state = conn.watch_connection_state();
+    timeout(
+        WAIT,
+        state.wait_for(|state| !matches!(state, APNSConnectionState::Connected { .. })),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+}
+
+#[tokio::test]
+async fn two_devices_round_trip() {
+    let courier = FakeCourier::new();
+    let (conn_a, user_a) = courier.new_device("mailto:a@example.com").await.unwrap();
+    let (conn_b, user_b) = courier.new_device("mailto:b@example.com").await.unwrap();
+    let client_a = IMClient::new(conn_a, Arc::new(vec![user_a])).await;
+    let client_b = IMClient::new(conn_b, Arc::new(vec![user_b])).await;
+
+    send_text(
+        &client_a,
+        "mailto:a@example.com",
+        "mailto:b@example.com",
+        "hello",
+    )
+    .await;
+    assert_eq!(recv_text(&client_b).await, "[mailto:a@example.com] 'hello'");
+
+    send_text(
+        &client_b,
+        "mailto:b@example.com",
+        "mailto:a@example.com",
+        "hi back",
+    )
+    .await;
+    assert_eq!(
+        recv_text(&client_a).await,
+        "[mailto:b@example.com] 'hi back'"
+    );
+}
+
+#[tokio::test]
+async fn delivers_after_reconnect() {
+    let courier = FakeCourier::new();
+    let (conn_a, user_a) = courier.new_device("mailto:a@example.com").await.unwrap();
+    let (conn_b, user_b) = courier.new_device("mailto:b@example.com").await.unwrap();
+    let client_a = IMClient::new(conn_a.clone(), Arc::new(vec![user_a])).await;
+    let client_b = IMClient::new(conn_b.clone(), Arc::new(vec![user_b])).await;
+
+    courier.drop_connections().await;
+    wait_disconnected(&conn_a).await;
+    // queued until the connection is back
+    send_text(
+        &client_a,
+        "mailto:a@example.com",
+        "mailto:b@example.com",
+        "while reconnecting",
+    )
+    .await;
+    assert_eq!(
+        recv_text(&client_b).await,
+        "[mailto:a@example.com] 'while reconnecting'"
+    );
+    wait_connected(&conn_a).await;
+    wait_connected(&conn_b).await;
+}
+
+#[tokio::test]
+async fn refilters_after_reconnect() {
+    let courier = FakeCourier::new();
+    let (conn, _) = courier.new_device("mailto:a@example.com").await.unwrap();
+    let token = conn.state().token.unwrap();
+    let mut subscription = conn.subscribe("com.example.topic").await;
+
+    courier.drop_connections().await;
+    wait_disconnected(&conn).await;
+    wait_connected(&conn).await;
+    // the courier forgets filters with the connection, so this only arrives if we filtered again
+    assert_eq!(conn.state().token.unwrap(), token);
+    courier.push(&token, "com.example.topic", b"after").await;
+
+    let received = timeout(WAIT, subscription.recv()).await.unwrap().unwrap();
+    let APNSCommand::Notification { payload, .. } = received else {
+        panic!("not a notification")
+    };
+    assert_eq!(payload, b"after");
+}
+
+#[tokio::test]
+async fn reconnects_after_missed_keep_alives() {
+    let courier = FakeCourier::new();
+    let (conn, _) = courier.new_device("mailto:a@example.com").await.unwrap();
+    conn.set_keep_alive_policy(KeepAlivePolicy {
+        interval: Duration::from_millis(100),
+        timeout: Duration::from_millis(100),
+        max_missed: 2,
+    });
+
+    courier.set_ignore_keep_alives(true).await;
+    wait_disconnected(&conn).await;
+    courier.set_ignore_keep_alives(false).await;
+    wait_connected(&conn).await;
+}
+
+#[tokio::test]
+async fn shutdown_ends_subscriptions() {
+    let courier = FakeCourier::new();
+    let (conn, _) = courier.new_device("mailto:a@example.com").await.unwrap();
+    let token = conn.state().token.unwrap();
+    let mut subscription = conn.subscribe("com.example.topic").await;
+
+    conn.shutdown().await.unwrap();
+    assert_eq!(conn.connection_state(), APNSConnectionState::Shutdown);
+    assert!(timeout(WAIT, subscription.recv()).await.unwrap().is_err());
+    assert_eq!(courier.device_state(&token).await, None);
+}
+
+#[tokio::test]
+async fn slow_subscriber_loses_nothing() {
+    let courier = FakeCourier::new();
+    let (conn, _) = courier.new_device("mailto:a@example.com").await.unwrap();
+    let token = conn.state().token.unwrap();
+    let options = SubscriberOptions {
+        capacity: 2,
+        overflow: OverflowPolicy::Block,
+    };
+    let mut subscription = conn
+        .subscribe_filtered("com.example.topic", options, |_| true)
+        .await;
+
+    for i in 0..10u8 {
+        courier.push(&token, "com.example.topic", &[i]).await;
+    }
+    for i in 0..10u8 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let received = timeout(WAIT, subscription.recv()).await.unwrap().unwrap();
+        let APNSCommand::Notification { payload, .. } = received else {
+            panic!("not a notification")
+        };
+        assert_eq!(payload, [i]);
+    }
+    assert_eq!(subscription.dropped(), 0);
+}
+
+#[tokio::test]
+async fn unread_subscriber_does_not_stall_sends() {
+    let courier = FakeCourier::new();
+    let (conn, _) = courier.new_device("mailto:a@example.com").await.unwrap();
+    let token = conn.state().token.unwrap();
+    let subscription = conn.subscribe("com.example.topic").await;
+
+    for i in 0..25u8 {
+        courier.push(&token, "com.example.topic", &[i]).await;
+    }
+    timeout(
+        WAIT,
+        conn.send_message_timeout("com.example.topic", b"ping", None, Duration::from_secs(1)),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    // the oldest were dropped to make room
+    assert!(subscription.dropped() > 0);
+}
+
+#[tokio::test]
+async fn blocked_subscriber_times_out_sends() {
+    let courier = FakeCourier::new();
+    let (conn, _) = courier.new_device("mailto:a@example.com").await.unwrap();
+    let token = conn.state().token.unwrap();
+    let options = SubscriberOptions {
+        capacity: 2,
+        overflow: OverflowPolicy::Block,
+    };
+    let _subscription = conn
+        .subscribe_filtered("com.example.topic", options, |_| true)
+        .await;
+
+    for i in 0..5u8 {
+        courier.push(&token, "com.example.topic", &[i]).await;
+    }
+    // the reader is stuck, so no ack arrives, but the send still gives up on time
+    let result = timeout(
+        WAIT,
+        conn.send_message_timeout("com.example.topic", b"ping", None, Duration::from_secs(1)),
+    )
+    .await
+    .unwrap();
+    assert!(matches!(result, Err(PushError::APNSTimeout)));
+}
+
+#[tokio::test]
+async fn connect_without_token_backs_off() {
+    let courier = FakeCourier::new();
+    let (conn, _) = courier.new_device("mailto:a@example.com").await.unwrap();
+    let mut state = conn.watch_connection_state();
+
+    // a new cert drops the token, and this courier won't hand out another
+    courier.set_withhold_tokens(true).await;
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    conn.set_push_cert(KeyPair {
+        cert: vec![],
+        private: key.private_key_to_der().unwrap(),
+    });
+
+    // the failed connect is noticed and backed off from, rather than left in Connecting
+    timeout(
+        WAIT,
+        state.wait_for(|state| matches!(state, APNSConnectionState::Backoff { retry: 1 })),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+}
+
+#[tokio::test]
+async fn reply_wait_times_out() {
+    let courier = FakeCourier::new();
+    let (conn, _) = courier.new_device("mailto:a@example.com").await.unwrap();
+    // acked, but nothing ever answers on this topic
+    let result = timeout(
+        WAIT,
+        conn.send_message_for_reply_timeout(
+            "com.example.topic",
+            b"ping",
+            None,
+            |_| true,
+            Duration::from_millis(500),
+        ),
+    )
+    .await
+    .unwrap();
+    assert!(matches!(result, Err(PushError::APNSTimeout)));
+}
+
+#[tokio::test]
+async fn ack_wait_pauses_while_reconnecting() {
+    let courier = FakeCourier::new();
+    let (conn, _) = courier.new_device("mailto:a@example.com").await.unwrap();
+
+    courier.set_hold_connects(true).await;
+    courier.drop_connections().await;
+    wait_disconnected(&conn).await;
+    let send = tokio::spawn({
+        let conn = conn.clone();
+        async move {
+            conn.send_message_timeout("com.example.topic", b"ping", None, Duration::from_secs(1))
+                .await
+        }
+    });
+    // reconnecting takes longer than the ack wait, which only starts once we're back
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    courier.set_hold_connects(false).await;
+    timeout(WAIT, send).await.unwrap().unwrap().unwrap();
+}
+
+async fn wait_filtering(courier: &FakeCourier, token: &[u8], topic: &str, filtering: bool) {
+    timeout(WAIT, async {
+        while courier.is_filtering(token, topic).await != filtering {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn dropped_subscription_stops_filtering() {
+    let courier = FakeCourier::new();
+    let (conn, _) = courier.new_device("mailto:a@example.com").await.unwrap();
+    let token = conn.state().token.unwrap();
+    let first = conn.subscribe("com.example.topic").await;
+    let second = conn.subscribe("com.example.topic").await;
+    wait_filtering(&courier, &token, "com.example.topic", true).await;
+
+    // still held by the other one
+    drop(first);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(courier.is_filtering(&token, "com.example.topic").await);
+
+    drop(second);
+    wait_filtering(&courier, &token, "com.example.topic", false).await;
+}
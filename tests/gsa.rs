@@ -0,0 +1,192 @@
+use rustpush::{PushError, SrpClient};
+
+// generated with an independent big-integer implementation of the same SRP-6a variant,
+// acting as both client and server with fixed private values
+struct Vector {
+    username: &'static str,
+    password: &'static str,
+    protocol: &'static str,
+    iterations: usize,
+    salt: &'static str,
+    password_key: &'static str,
+    a: &'static str,
+    big_a: &'static str,
+    big_b: &'static str,
+    session_key: &'static str,
+    m1: &'static str,
+    m2: &'static str,
+}
+
+const VECTORS: [Vector; 2] = [
+    Vector {
+        username: "test@example.com",
+        password: "correct horse battery staple",
+        protocol: "s2k",
+        iterations: 20000,
+        salt: "c224775fb02897110eaa54fe420975ec",
+        password_key: "d6119343e39101e6130b4b89251e268019f4c81021c26fe9817da0ee4844d94f",
+        a: "ad147d27a6ba78b6594f0983d4660c2d434fa55c93027c80998a543a57222ba8",
+        big_a: concat!(
+            "a9e644d7ff7b70263459e15dee538632e2f7eb8f74a19452bd1e2ba470993693",
+            "7aab3a1f405901123ebe9801235ba302bbe023884144cdbb373c49e753b515fc",
+            "531b78848181dcfd08be0a9ecd3cbecdeb7a9c27f2209b9fd4005b57225035da",
+            "271ec80f776e05942c5a26e77646aead142dc4428fee038063c9ef0afb008c07",
+            "696d19a7262fb4f5bba7700e14c2c55389534e901c298e54f8e4bcccf6fed6fb",
+            "23e66128e51249cfac9ec4c025ebf55dd33bdb1112561a865d33d2df214903a3",
+            "54635f25a8dda5c1e4777bec2363c70abbb0e3fcf32428114ce4252bddefaaa1",
+            "c2776b05e5c555ab47bd4fcf8742086185bcd932901aad51a0296829fc299bfa",
+        ),
+        big_b: concat!(
+            "13f78621c73935ba10b3902af86f57abeb32bb22cdaf9a9d003bf85e28d20fc6",
+            "1ead2d108f948e01a1d097f69f26f13b0fcbc85776e75c636481f5ba0b2b3405",
+            "368f70974262eed0529aed85bce5cbe28f33f8bb42ae5881d69e4b366a09b254",
+            "d7b6f4e3319951b43f78eb06f066320394670ed90e405054ca978b4e96767f26",
+            "6074f9323e7be22e1c5bc93fcc9934f95a5623b9e1531562fb3b570ae75e78ee",
+            "3ed5a541c9fba700781850b997c434aeb7ab59ffd682eea26da961afa169cc58",
+            "21d2033ed7fddc17d20b1b7c35171ecc751a8dc98b3c960d12046973d1265301",
+            "301b20e002cad24dcf7d073e6fbc75862185726bf921381ddddd43f6fef8322c",
+        ),
+        session_key: "3115fffbdcceb2906cc145b299f18bdc4643c126e0c74ec9d8ce42c793edb7ac",
+        m1: "d07b714e283257864be25cd52e6aeba2178cdbf3f35a2abd9cee30cbd54a8774",
+        m2: "70e3ea53941520f4f1f11c465badbdc4047ec48d746692e3fc93d50737a9389d",
+    },
+    Vector {
+        username: "alice@icloud.com",
+        password: "password123",
+        protocol: "s2k_fo",
+        iterations: 1000,
+        salt: "a9aaf581e13b215daee8ff7e51d15d25",
+        password_key: "d823d2b7183693e41e572f66f17e7546b9d6d3d2217bfbb7a5247ab3a10bd48f",
+        a: "64980b993a7114daf69f0b38eaf6ad4a745d5c21e020d133573cf79319781e08",
+        big_a: concat!(
+            "4f5319a137bd1cb8521e996e277bd1443b340e65ab4a462ac58a1c1b0a67f946",
+            "b5ba286995706824420bb53dd13e8bd57d65406a6de00c6fe9845b0a126c7a0b",
+            "df887dc24f283aa54bc66c648a469c2af3c26d6554e072caf10329b2d8a78167",
+            "06283d3cd70d5fdfcdfea3a5bb6e64c48bbc96ffcfe4cc087c23980fca456f96",
+            "52efcebf896938ee1466bf2199e508a8dd58fdaa8254a3548ef2b890a301332a",
+            "343386d91a4d217469966519b37658ad66e3d38811480f005fcee6eae6c137b9",
+            "0384e9804eea8d0b2e2e76e96a9b8be7e6a879c3c7a43da86922f8b2082f596f",
+            "d5470eb21554c5efbe0e5c5e744813f7cb125ec728bb40d7bba1fb8104b6f438",
+        ),
+        big_b: concat!(
+            "27407bc4a32322fb81b852de4d6d77c6d0a3e7b713bc082279583253fd7cfed2",
+            "d3b1667e2fefe572d641a95bb42d252b7739145bef5abaed21087cdec5b667ed",
+            "a4d07f5c926d47b5a96a7057e2b35dd70e8528aca4382072bd7e7b7ffb0dfec1",
+            "489e88de1f96e8ec790e75c51eeb2f37d6ef44fbd536e5b8514833c9d3eec637",
+            "cf5d5f3e080b4e0e7592972555b6c11a4853c80b4914a1f19e8ce6d0a4bba245",
+            "d16f6c223da484dda8c08f0019c0175a8f23b1685fc7023ff719e9d46191ec17",
+            "051dc63eae25febf4570ee192cd854c020def2806e9c7affdecc7145efe1cd8c",
+            "31e87058118c0f9009db4217854466982473da8b8002426f9ef8f88c63e8014e",
+        ),
+        session_key: "d7bd01e4cc32845f05b71308df662017643c6f987dfe825e3c44eb906b4442bf",
+        m1: "f0303f7db10cdfd7225a91e866abeed15e54770935268ddd639138fba1152ba9",
+        m2: "3e1eec27c00c0cb050ff073b3658ca3a54f522b1a8f8184f0f5c3b2493eee99e",
+    },
+];
+
+fn unhex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn password_key() {
+    for vector in VECTORS {
+        let key = SrpClient::password_key(
+            vector.password,
+            &unhex(vector.salt),
+            vector.iterations,
+            vector.protocol,
+        )
+        .unwrap();
+        assert_eq!(key, unhex(vector.password_key), "{}", vector.protocol);
+    }
+}
+
+#[test]
+fn unsupported_protocol() {
+    assert!(matches!(
+        SrpClient::password_key("password", b"salt", 1000, "s2k_plain"),
+        Err(PushError::SRPError(_))
+    ));
+}
+
+#[test]
+fn public_key() {
+    for vector in VECTORS {
+        let client = SrpClient::with_private(&unhex(vector.a)).unwrap();
+        assert_eq!(client.public_key(), unhex(vector.big_a));
+    }
+}
+
+#[test]
+fn exchange() {
+    for vector in VECTORS {
+        let mut client = SrpClient::with_private(&unhex(vector.a)).unwrap();
+        let m1 = client
+            .process_challenge(
+                vector.username,
+                &unhex(vector.password_key),
+                &unhex(vector.salt),
+                &unhex(vector.big_b),
+            )
+            .unwrap();
+        assert_eq!(m1, unhex(vector.m1));
+        assert_eq!(client.session_key().unwrap(), unhex(vector.session_key));
+        client.verify_server(&unhex(vector.m2)).unwrap();
+    }
+}
+
+#[test]
+fn rejects_wrong_server_proof() {
+    let vector = &VECTORS[0];
+    let mut client = SrpClient::with_private(&unhex(vector.a)).unwrap();
+    assert!(matches!(
+        client.verify_server(&unhex(vector.m2)),
+        Err(PushError::SRPError(_))
+    ));
+    client
+        .process_challenge(
+            vector.username,
+            &unhex(vector.password_key),
+            &unhex(vector.salt),
+            &unhex(vector.big_b),
+        )
+        .unwrap();
+    assert!(matches!(
+        client.verify_server(&unhex(VECTORS[1].m2)),
+        Err(PushError::SRPError(_))
+    ));
+}
+
+#[test]
+fn wrong_password_changes_proof() {
+    let vector = &VECTORS[0];
+    let mut client = SrpClient::with_private(&unhex(vector.a)).unwrap();
+    let m1 = client
+        .process_challenge(
+            vector.username,
+            &unhex(VECTORS[1].password_key),
+            &unhex(vector.salt),
+            &unhex(vector.big_b),
+        )
+        .unwrap();
+    assert_ne!(m1, unhex(vector.m1));
+}
+
+#[test]
+fn rejects_zero_server_key() {
+    let vector = &VECTORS[0];
+    let mut client = SrpClient::new().unwrap();
+    assert!(matches!(
+        client.process_challenge(
+            vector.username,
+            &unhex(vector.password_key),
+            &unhex(vector.salt),
+            &[0],
+        ),
+        Err(PushError::SRPError(_))
+    ));
+}
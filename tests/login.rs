@@ -0,0 +1,82 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rustpush::{
+    AppleIDAuthenticator, AppleIDLogin, AuthResponse, IDSAppleUser, PendingLogin, PushError,
+    SecondFactor, TwoFactorCode,
+};
+
+// records the codes it's given and rejects them all, so nothing reaches IDS
+struct StubPending {
+    second_factor: SecondFactor,
+    submitted: Arc<Mutex<Vec<TwoFactorCode>>>,
+}
+
+#[async_trait]
+impl PendingLogin for StubPending {
+    fn second_factor(&self) -> SecondFactor {
+        self.second_factor
+    }
+
+    async fn request_sms(&mut self) -> Result<(), PushError> {
+        self.second_factor = SecondFactor::Sms;
+        Ok(())
+    }
+
+    async fn submit_code(&self, code: &TwoFactorCode) -> Result<(String, String), PushError> {
+        self.submitted.lock().unwrap().push(code.clone());
+        Err(PushError::TwoFaCodeRejected)
+    }
+}
+
+struct StubAuthenticator {
+    submitted: Arc<Mutex<Vec<TwoFactorCode>>>,
+}
+
+#[async_trait]
+impl AppleIDAuthenticator for StubAuthenticator {
+    async fn authenticate(
+        &self,
+        _username: &str,
+        _password: &str,
+    ) -> Result<AuthResponse, PushError> {
+        Ok(AuthResponse::NeedsTwoFactor(Box::new(StubPending {
+            second_factor: SecondFactor::TrustedDevice,
+            submitted: self.submitted.clone(),
+        })))
+    }
+}
+
+#[tokio::test]
+async fn challenge_switches_to_sms() {
+    let submitted = Arc::new(Mutex::new(vec![]));
+    let authenticator = StubAuthenticator {
+        submitted: submitted.clone(),
+    };
+    let login = IDSAppleUser::start_login(&authenticator, "a@example.com", "hunter2")
+        .await
+        .unwrap();
+    let AppleIDLogin::NeedsTwoFactor(mut challenge) = login else {
+        panic!("expected a challenge")
+    };
+    assert_eq!(challenge.username(), "a@example.com");
+    assert_eq!(challenge.second_factor(), SecondFactor::TrustedDevice);
+
+    challenge.request_sms().await.unwrap();
+    assert_eq!(challenge.second_factor(), SecondFactor::Sms);
+
+    // a rejected code leaves the challenge usable
+    for code in ["111111", "222222"] {
+        let result = challenge
+            .submit_code(TwoFactorCode::Sms(code.to_string()))
+            .await;
+        assert!(matches!(result, Err(PushError::TwoFaCodeRejected)));
+    }
+    assert_eq!(
+        *submitted.lock().unwrap(),
+        [
+            TwoFactorCode::Sms("111111".to_string()),
+            TwoFactorCode::Sms("222222".to_string())
+        ]
+    );
+}